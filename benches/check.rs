@@ -0,0 +1,106 @@
+//! Compares the strategies to check the token while reading.
+//!
+//! * `plain`: the inner reader alone, without a `Cancellable`.
+//! * `per_call`: the default, the token is checked on every call.
+//! * `count_interval`: checked once every 16 calls. There is no option of
+//!   `Cancellable` for that, so it is done here by a small wrapper, as a user
+//!   would.
+//! * `time_interval`: checked at most once every millisecond, see
+//!   `Cancellable::checkpoint_every`.
+//! * `chunked`: every call is split in 4 KiB chunks, see `Cancellable::chunked`.
+//!
+//! Each one does a `read_exact` of a small (64 B) and a large (64 KiB) buffer
+//! from `io::repeat`. Unless it is chunked, `Cancellable` checks the token
+//! once and delegates the `read_exact` to the inner reader.
+//!
+//! Some results, on a single core Xeon virtual machine:
+//!
+//! | strategy         | 64 B   | 64 KiB |
+//! |------------------|--------|--------|
+//! | `plain`          | 2.3 ns | 1.4 µs |
+//! | `per_call`       | 13 ns  | 1.6 µs |
+//! | `count_interval` | 3.2 ns | 1.5 µs |
+//! | `time_interval`  | 51 ns  | 1.4 µs |
+//! | `chunked`        | 16 ns  | 1.7 µs |
+//!
+//! The check itself is a single relaxed atomic load, so all the strategies cost
+//! about the same for big operations. For small ones the wrapper is noticeable:
+//! most of the cost of `per_call` is the bookkeeping of `Cancellable`, not the
+//! check, which is why the bare `count_interval` wrapper is so close to
+//! `plain`. The rarely used options are kept out of the way, so without them
+//! that bookkeeping is a few loads and stores. Skipping checks by count would
+//! not save much inside `Cancellable`, and it makes the latency depend on the
+//! size of the operations, so it is not offered. The time interval is the
+//! slowest, because reading the clock costs more than checking the token: it
+//! only helps if the check is expensive, for example because the token is
+//! shared by many threads. Chunking adds little overhead, and makes big
+//! operations cancellable.
+
+use cancel_rw::{Cancellable, CancellationToken};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::hint::black_box;
+use std::io::{self, Read};
+use std::time::Duration;
+
+// Checks the token once every `N` reads.
+struct EveryN<R> {
+    inner: R,
+    token: CancellationToken,
+    count: u32,
+}
+
+const N: u32 = 16;
+
+impl<R: Read> Read for EveryN<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.count += 1;
+        if self.count == N {
+            self.count = 0;
+            self.token.check()?;
+        }
+        self.inner.read(buf)
+    }
+}
+
+fn check(c: &mut Criterion) {
+    let mut group = c.benchmark_group("check");
+
+    for size in [64, 64 * 1024] {
+        let mut buf = vec![0; size];
+
+        let mut r = io::repeat(1);
+        group.bench_with_input(BenchmarkId::new("plain", size), &size, |b, _| {
+            b.iter(|| r.read_exact(black_box(&mut buf)).unwrap())
+        });
+
+        let mut r = Cancellable::new(io::repeat(1), CancellationToken::new());
+        group.bench_with_input(BenchmarkId::new("per_call", size), &size, |b, _| {
+            b.iter(|| r.read_exact(black_box(&mut buf)).unwrap())
+        });
+
+        let mut r = EveryN {
+            inner: io::repeat(1),
+            token: CancellationToken::new(),
+            count: 0,
+        };
+        group.bench_with_input(BenchmarkId::new("count_interval", size), &size, |b, _| {
+            b.iter(|| r.read_exact(black_box(&mut buf)).unwrap())
+        });
+
+        let mut r = Cancellable::new(io::repeat(1), CancellationToken::new())
+            .checkpoint_every(Duration::from_millis(1));
+        group.bench_with_input(BenchmarkId::new("time_interval", size), &size, |b, _| {
+            b.iter(|| r.read_exact(black_box(&mut buf)).unwrap())
+        });
+
+        let mut r = Cancellable::new(io::repeat(1), CancellationToken::new()).chunked(4096);
+        group.bench_with_input(BenchmarkId::new("chunked", size), &size, |b, _| {
+            b.iter(|| r.read_exact(black_box(&mut buf)).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, check);
+criterion_main!(benches);
@@ -0,0 +1,23 @@
+//! Compares writing through a `Cancellable` with static and dynamic dispatch.
+
+use cancel_rw::{Cancellable, CancellationToken};
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+use std::io::{self, Write};
+
+const DATA: [u8; 64] = [0; 64];
+
+fn dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dispatch");
+
+    let mut w = Cancellable::new(io::sink(), CancellationToken::new());
+    group.bench_function("static", |b| b.iter(|| w.write(black_box(&DATA)).unwrap()));
+
+    let mut w = Cancellable::new(io::sink(), CancellationToken::new()).boxed_write();
+    group.bench_function("dynamic", |b| b.iter(|| w.write(black_box(&DATA)).unwrap()));
+
+    group.finish();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
@@ -0,0 +1,78 @@
+//! Compares concurrent positioned reads with and without a `Cancellable`.
+//!
+//! Every iteration is a 64 B `read_exact_at` from a shared 1 MiB buffer, done
+//! by each of `THREADS` threads at the same time.
+//!
+//! * `plain`: the shared buffer alone.
+//! * `shared_token`: a shared `Cancellable`, with its token.
+//! * `token_per_thread`: a `Cancellable` for each thread, with clones of the
+//!   same token.
+//!
+//! Some results, per read and thread, on a single core Xeon virtual machine:
+//!
+//! | benchmark          | time  |
+//! |--------------------|-------|
+//! | `plain`            | 22 ns |
+//! | `shared_token`     | 49 ns |
+//! | `token_per_thread` | 46 ns |
+//!
+//! With a single core the threads do not really run in parallel, so these
+//! numbers show the cost of the wrapper but not the contention. Checking the
+//! token is a relaxed atomic load, and a `Cancellable` that is not cancelled
+//! does not write to memory shared by the threads, so there should be no
+//! contention beyond sharing the cache line, unless the wrapper is configured
+//! with an operation limit or `Cancellable::checkpoint_every`.
+
+use cancel_rw::{Cancellable, CancellationToken};
+use criterion::{criterion_group, criterion_main, Criterion};
+use positioned_io::ReadAt;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const THREADS: usize = 4;
+const SIZE: usize = 1024 * 1024;
+
+// Runs `iters` reads in every thread, returns the time it took.
+fn run<R: ReadAt + Sync>(readers: &[R], iters: u64) -> Duration {
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for (i, r) in readers.iter().enumerate() {
+            s.spawn(move || {
+                let mut buf = [0; 64];
+                let mut pos = i as u64 * 4096;
+                for _ in 0..iters {
+                    r.read_exact_at(pos, black_box(&mut buf)).unwrap();
+                    pos = (pos + 4160) % (SIZE as u64 - 64);
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn positioned(c: &mut Criterion) {
+    let mut group = c.benchmark_group("positioned");
+    let data = vec![1; SIZE];
+
+    let plain = vec![&data[..]; THREADS];
+    group.bench_function("plain", |b| b.iter_custom(|iters| run(&plain, iters)));
+
+    let shared = Cancellable::new(&data[..], CancellationToken::new());
+    let shared = vec![&shared; THREADS];
+    group.bench_function("shared_token", |b| {
+        b.iter_custom(|iters| run(&shared, iters))
+    });
+
+    let ct = CancellationToken::new();
+    let per_thread: Vec<_> = (0..THREADS)
+        .map(|_| Cancellable::new(&data[..], ct.clone()))
+        .collect();
+    group.bench_function("token_per_thread", |b| {
+        b.iter_custom(|iters| run(&per_thread, iters))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, positioned);
+criterion_main!(benches);
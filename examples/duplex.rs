@@ -0,0 +1,41 @@
+//! Talks to a small echo server through a single `Cancellable<TcpStream>`.
+//!
+//! The same wrapper, and so the same token, is used both for reading and for
+//! writing. After one second the token is cancelled, and both directions stop.
+
+use cancel_rw::{Cancellable, CancellationToken};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+fn main() -> io::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || -> io::Result<()> {
+        let (stream, _) = listener.accept()?;
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            writeln!(writer, "{}", line?)?;
+        }
+        Ok(())
+    });
+
+    let ct = CancellationToken::new();
+    ct.cancel_after(Duration::from_secs(1));
+    let mut stream = BufReader::new(Cancellable::new(TcpStream::connect(addr)?, ct));
+    let mut n = 0;
+    let res = loop {
+        n += 1;
+        if let Err(e) = writeln!(stream.get_mut(), "message {n}") {
+            break e;
+        }
+        let mut line = String::new();
+        if let Err(e) = stream.read_line(&mut line) {
+            break e;
+        }
+        println!("echo: {}", line.trim_end());
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    println!("stopped after {n} messages: {res}");
+    Ok(())
+}
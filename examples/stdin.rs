@@ -0,0 +1,56 @@
+//! Reads lines from the standard input until the user types `quit` or a
+//! timeout of 10 seconds expires.
+//!
+//! A read from a terminal blocks in the kernel, and no token check can
+//! interrupt it. So the input is read in a separate thread that sends the
+//! lines through a channel, and the main thread waits on that channel checking
+//! the token every now and then.
+
+use cancel_rw::{Cancellable, CancellationToken};
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+fn main() -> io::Result<()> {
+    let ct = CancellationToken::new();
+
+    std::thread::spawn({
+        let ct = ct.clone();
+        move || {
+            std::thread::sleep(Duration::from_secs(10));
+            ct.cancel();
+        }
+    });
+
+    let (tx, rx) = mpsc::channel();
+    // This thread may be left blocked forever, it will die with the process.
+    // With the token it stops at the next line after the cancellation.
+    std::thread::spawn({
+        let ct = ct.clone();
+        move || {
+            let stdin = Cancellable::new(io::stdin().lock(), ct);
+            for line in stdin.lines() {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut stdout = Cancellable::new(io::stdout().lock(), ct.clone());
+    loop {
+        let line = match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(line) => line?,
+            Err(RecvTimeoutError::Timeout) => {
+                ct.check()?;
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if line == "quit" {
+            break;
+        }
+        writeln!(stdout, "> {line}")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use crate::CancellationToken;
+use std::time::Instant;
+
+impl CancellationToken {
+    /// Acknowledges the cancellation of this token.
+    ///
+    /// A worker calls this when it has finished reacting to the cancellation,
+    /// so that whoever cancelled it knows that it can go on, see
+    /// [`CancellationToken::wait_acks`]. Nothing checks that the token is
+    /// actually cancelled, or that every worker acknowledges only once.
+    pub fn ack(&self) {
+        self.inner.state.lock().unwrap().acks += 1;
+        self.inner.cond.notify_all();
+    }
+    /// Gets the number of acknowledgements of this token.
+    pub fn acks(&self) -> usize {
+        self.inner.state.lock().unwrap().acks
+    }
+    /// Waits until this token has at least `expected` acknowledgements.
+    ///
+    /// It returns `false` if `deadline` is reached before that.
+    pub fn wait_acks(&self, expected: usize, deadline: Instant) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+        while state.acks < expected {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self
+                .inner
+                .cond
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+    /// Cancels some tokens in order, waiting for their acknowledgements.
+    ///
+    /// Each stage is a token and the number of acknowledgements expected for
+    /// it. The token of a stage is cancelled and then this waits for its
+    /// acknowledgements, see [`CancellationToken::ack`], before going on to
+    /// the next stage. This is useful for a staged graceful shutdown, such as
+    /// first draining the inbound data, then flushing and then closing.
+    ///
+    /// All the stages must be done before `deadline`. Each stage waits at most
+    /// for its share of the time left, that is divided evenly among it and
+    /// the following stages, so a stage that is acknowledged early leaves more
+    /// time for the others. A stage that is not fully acknowledged in time does
+    /// not stop the chain, but then this returns `false`.
+    pub fn cancel_chain(stages: &[(CancellationToken, usize)], deadline: Instant) -> bool {
+        let mut all_acked = true;
+        for (i, (token, expected)) in stages.iter().enumerate() {
+            token.cancel();
+            let left = deadline.saturating_duration_since(Instant::now());
+            let share = left / (stages.len() - i) as u32;
+            all_acked &= token.wait_acks(*expected, Instant::now() + share);
+        }
+        all_acked
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_cancel_chain() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let stages: Vec<_> = (0..3).map(|_| (CancellationToken::new(), 2)).collect();
+        let mut workers = Vec::new();
+        for (stage, (token, _)) in stages.iter().enumerate() {
+            for _ in 0..2 {
+                let token = token.clone();
+                let log = log.clone();
+                workers.push(std::thread::spawn(move || {
+                    while token.check().is_ok() {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                    log.lock().unwrap().push(stage);
+                    token.ack();
+                }));
+            }
+        }
+        let deadline = Instant::now() + Duration::from_secs(30);
+        assert!(CancellationToken::cancel_chain(&stages, deadline));
+        // Every stage was cancelled only after the previous one was acknowledged.
+        assert_eq!(*log.lock().unwrap(), [0, 0, 1, 1, 2, 2]);
+        for w in workers {
+            w.join().unwrap();
+        }
+
+        // A missing acknowledgement times out, but the chain goes on.
+        let stages = [(CancellationToken::new(), 1), (CancellationToken::new(), 0)];
+        let deadline = Instant::now() + Duration::from_millis(20);
+        assert!(!CancellationToken::cancel_chain(&stages, deadline));
+        assert!(stages[1].0.check().is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use crate::CancellationToken;
+use std::time::{Duration, Instant};
+
+/// A builder of a token that is cancelled by the first of several triggers.
+///
+/// It is created with [`CancellationToken::cancelled_by`]. The built token can
+/// also be cancelled manually, as any other token.
+#[derive(Debug, Default)]
+#[must_use = "the token is only created by `build`"]
+pub struct CancelBuilder {
+    deadline: Option<Instant>,
+    #[cfg(all(unix, feature = "signal"))]
+    signals: Vec<i32>,
+}
+
+impl CancellationToken {
+    /// Starts building a token that is cancelled by the first of several triggers.
+    ///
+    /// See [`CancelBuilder`].
+    pub fn cancelled_by() -> CancelBuilder {
+        CancelBuilder::default()
+    }
+}
+
+impl CancelBuilder {
+    /// Cancels the token after `timeout`, counting from now.
+    ///
+    /// See [`CancellationToken::cancel_after`].
+    pub fn after(self, timeout: Duration) -> Self {
+        self.at(Instant::now() + timeout)
+    }
+    /// Cancels the token when `deadline` is reached.
+    ///
+    /// If it is called many times, the earliest deadline is used. See
+    /// [`CancellationToken::cancel_at`].
+    pub fn at(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(self.deadline.map_or(deadline, |d| d.min(deadline)));
+        self
+    }
+    /// Cancels the token when the signal `sig` is delivered to the process.
+    ///
+    /// See [`CancellationToken::add_signal`].
+    #[cfg(all(unix, feature = "signal"))]
+    pub fn on_signal(mut self, sig: i32) -> Self {
+        self.signals.push(sig);
+        self
+    }
+    /// Cancels the token when the user presses Ctrl-C, that is, on `SIGINT`.
+    #[cfg(all(unix, feature = "signal"))]
+    pub fn on_ctrl_c(self) -> Self {
+        self.on_signal(signal_hook::consts::SIGINT)
+    }
+    /// Creates the token and starts all the triggers.
+    ///
+    /// It fails only if a signal cannot be registered.
+    pub fn build(self) -> std::io::Result<CancellationToken> {
+        let token = CancellationToken::new();
+        #[cfg(all(unix, feature = "signal"))]
+        for sig in self.signals {
+            token.add_signal(sig)?;
+        }
+        if let Some(deadline) = self.deadline {
+            token.cancel_at(deadline);
+        }
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::CancelCause;
+
+    #[test]
+    fn test_cancelled_by() {
+        let ct = CancellationToken::cancelled_by()
+            .after(Duration::from_secs(60))
+            .after(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        assert!(ct.check().is_ok());
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(ct.cancel_cause(), Some(CancelCause::Deadline));
+
+        // A manual cancellation comes first.
+        let ct = CancellationToken::cancelled_by()
+            .after(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        ct.cancel();
+        assert_eq!(ct.cancel_cause(), Some(CancelCause::Manual));
+
+        // Without triggers it is a plain token.
+        let ct = CancellationToken::cancelled_by().build().unwrap();
+        assert!(ct.check().is_ok());
+        assert_eq!(ct.deadline(), None);
+    }
+}
@@ -0,0 +1,642 @@
+use crate::{classify, Cancellable, Outcome};
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+const FLUSH_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+impl<T> Cancellable<T> {
+    // The start of a call, only taken if it has a time budget, so that the
+    // calls without one do not read the clock.
+    fn call_start(&self) -> Option<Instant> {
+        self.call_budget().map(|_| Instant::now())
+    }
+    // Checks the token, and the time budget of a call started at `start`.
+    fn check_call(&self, start: Option<Instant>) -> io::Result<()> {
+        self.check_token()?;
+        match (self.call_budget(), start) {
+            (Some(budget), Some(start)) if start.elapsed() > budget => {
+                Err(io::ErrorKind::TimedOut.into())
+            }
+            _ => Ok(()),
+        }
+    }
+    fn call_budget(&self) -> Option<Duration> {
+        self.options.as_ref().and_then(|o| o.call_timeout)
+    }
+    // The length of the next inner operation, for a buffer of `len` bytes.
+    pub(crate) fn chunk_len(&self, len: usize) -> usize {
+        match self.chunk_size {
+            Some(size) => len.min(size),
+            None => len,
+        }
+    }
+    // Like `chunk_len`, for an inner write, see `Cancellable::max_write`.
+    pub(crate) fn write_len(&self, len: usize) -> usize {
+        match self.max_write {
+            Some(max) => self.chunk_len(len).min(max),
+            None => self.chunk_len(len),
+        }
+    }
+}
+
+impl<T: Read> Cancellable<T> {
+    /// Reads into `buf` until it is full, the end of the stream, or an error.
+    ///
+    /// Unlike `read_exact`, it returns the number of bytes read even if it
+    /// fails, together with the error, so that a cancelled transfer can be
+    /// resumed later. The error is `None` if the buffer was filled or the end
+    /// of the stream was reached.
+    pub fn read_until_cancelled(&mut self, buf: &mut [u8]) -> (usize, Option<io::Error>) {
+        let mut total = 0;
+        while total < buf.len() {
+            match self.read(&mut buf[total..]) {
+                Ok(0) => break,
+                Ok(n) => total += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return (total, Some(e)),
+            }
+        }
+        (total, None)
+    }
+
+    pub(crate) fn chunked_read_exact(&mut self, mut buf: &mut [u8]) -> io::Result<()> {
+        let start = self.call_start();
+        while !buf.is_empty() {
+            self.check_call(start)?;
+            let len = self.chunk_len(buf.len());
+            match self.retry("read", |r| r.read(&mut buf[..len]))? {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ))
+                }
+                Ok(n) => {
+                    self.record_size(n);
+                    buf = &mut std::mem::take(&mut buf)[n..];
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn chunked_read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let mut chunk = std::mem::take(&mut self.scratch);
+        if chunk.is_empty() {
+            chunk.resize(self.chunk_size.unwrap_or(crate::DEFAULT_CHUNK_SIZE), 0);
+        }
+        let res = self.read_to_end_with(buf, &mut chunk);
+        self.scratch = chunk;
+        res
+    }
+
+    fn read_to_end_with(&mut self, buf: &mut Vec<u8>, chunk: &mut [u8]) -> io::Result<usize> {
+        let start = self.call_start();
+        let len = self.chunk_len(chunk.len());
+        let mut total = 0;
+        loop {
+            self.check_call(start)?;
+            match self.retry("read", |r| r.read(&mut chunk[..len]))? {
+                Ok(0) => {
+                    self.record_size(0);
+                    return Ok(total);
+                }
+                Ok(n) => {
+                    self.record_size(n);
+                    buf.extend_from_slice(&chunk[..n]);
+                    total += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // See `Cancellable::min_size`. The token has already been checked.
+    pub(crate) fn read_min(&mut self, buf: &mut [u8], min_size: usize) -> io::Result<usize> {
+        let want = min_size.min(buf.len());
+        let mut total = 0;
+        loop {
+            let len = self.chunk_len(buf.len() - total);
+            match self.retry("read", |r| r.read(&mut buf[total..total + len])) {
+                Ok(Ok(0)) => break,
+                Ok(Ok(n)) => {
+                    self.record_size(n);
+                    total += n;
+                    if total >= want {
+                        break;
+                    }
+                }
+                Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+                Ok(Err(_)) | Err(_) if total > 0 => break,
+                Ok(Err(e)) => return self.inner_count(Err(e)),
+                Err(e) => return self.cancelled_count(e),
+            }
+            if total > 0 && self.check_token().is_err() {
+                break;
+            }
+        }
+        self.inspected(Ok(total))
+    }
+
+    // The bytes are read as in `read_to_end`, straight into `buf`, and then
+    // validated a chunk at a time, checking the token between chunks, because
+    // for a huge input it takes a while. As in std, invalid UTF-8 is never
+    // left in `buf`, and the valid data read before an inner error is kept,
+    // but if the token is cancelled nothing is added.
+    pub(crate) fn chunked_read_to_string(&mut self, buf: &mut String) -> io::Result<usize> {
+        // Removes the new bytes on drop unless they are accepted, so that the
+        // string never holds invalid UTF-8, even if the inner value panics.
+        struct Guard<'a> {
+            buf: &'a mut Vec<u8>,
+            len: usize,
+        }
+
+        impl Drop for Guard<'_> {
+            fn drop(&mut self) {
+                self.buf.truncate(self.len);
+            }
+        }
+
+        let len = buf.len();
+        // SAFETY: the guard removes the new bytes unless they are valid UTF-8.
+        let mut g = Guard {
+            buf: unsafe { buf.as_mut_vec() },
+            len,
+        };
+        let res = self.chunked_read_to_end(g.buf);
+        if let Err(e) = &res {
+            if matches!(classify(e), Outcome::Cancelled(_)) {
+                // Keep the characters read before the cancellation, without
+                // a last one that is incomplete, unless the inner value is
+                // rewound to read them again.
+                if self.rewind.is_none() {
+                    g.len += match std::str::from_utf8(&g.buf[len..]) {
+                        Ok(s) => s.len(),
+                        Err(e) => e.valid_up_to(),
+                    };
+                }
+                return res;
+            }
+        }
+        match (self.check_utf8(&g.buf[len..]), res) {
+            (Ok(()), res) => {
+                g.len = g.buf.len();
+                res
+            }
+            (Err(e), Ok(_)) => Err(e),
+            (Err(_), Err(e)) => Err(e),
+        }
+    }
+
+    fn check_utf8(&self, bytes: &[u8]) -> io::Result<()> {
+        let len = crate::DEFAULT_CHUNK_SIZE;
+        let mut pos = 0;
+        while pos < bytes.len() {
+            self.check_token()?;
+            let end = bytes.len().min(pos + len);
+            match std::str::from_utf8(&bytes[pos..end]) {
+                Ok(_) => pos = end,
+                // A character split between this chunk and the next one.
+                Err(e) if e.error_len().is_none() && end < bytes.len() => pos += e.valid_up_to(),
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "stream did not contain valid UTF-8",
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Write> Cancellable<T> {
+    /// Writes `buf` until all of it is written, or an error.
+    ///
+    /// Unlike `write_all`, it returns the number of bytes written even if it
+    /// fails, together with the error, so that a cancelled transfer can be
+    /// resumed later. If the inner value does not accept any more bytes, the
+    /// error is `ErrorKind::WriteZero`.
+    pub fn write_until_cancelled(&mut self, buf: &[u8]) -> (usize, Option<io::Error>) {
+        let mut total = 0;
+        while total < buf.len() {
+            match self.write(&buf[total..]) {
+                Ok(0) => return (total, Some(io::ErrorKind::WriteZero.into())),
+                Ok(n) => total += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return (total, Some(e)),
+            }
+        }
+        (total, None)
+    }
+
+    /// Writes all of `bufs`, checking the token between the inner writes.
+    ///
+    /// This is a stable version of `Write::write_all_vectored`: it calls
+    /// `write_vectored` until all the slices are written, advancing past the
+    /// ones that are fully written, so `bufs` is modified. A cancellation
+    /// fails with the cancellation error, even in graceful mode, see
+    /// [`Cancellable::graceful`], and then an unknown part of the data has
+    /// been written.
+    pub fn write_all_vectored(&mut self, mut bufs: &mut [io::IoSlice<'_>]) -> io::Result<()> {
+        io::IoSlice::advance_slices(&mut bufs, 0);
+        while !bufs.is_empty() {
+            match self.write_vectored(bufs) {
+                Ok(0) => {
+                    self.check_token()?;
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ));
+                }
+                Ok(n) => io::IoSlice::advance_slices(&mut bufs, n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `f`, deferring all the flushes until it finishes.
+    ///
+    /// This is for a logical write done in many pieces by code that flushes
+    /// after each of them, such as writing a chunked message: the token is
+    /// still checked between the pieces, but the calls to `flush` inside `f`
+    /// do nothing, and if there were any, the inner value is flushed only once,
+    /// when `f` succeeds.
+    ///
+    /// If `f` fails, for example because the token is cancelled, the deferred
+    /// flush is not done, so the data written so far may be left in a buffer
+    /// of the inner value, with no guarantee that it ever reaches its
+    /// destination.
+    pub fn coalesce_flushes<R>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let outer = self.deferred_flush.replace(false);
+        let res = f(self);
+        let deferred = std::mem::replace(&mut self.deferred_flush, outer) == Some(true);
+        let res = res?;
+        if deferred {
+            self.flush()?;
+        }
+        Ok(res)
+    }
+
+    /// Flushes the inner value, checking the token between attempts.
+    ///
+    /// If the inner flush fails with `ErrorKind::Interrupted` or
+    /// `ErrorKind::WouldBlock`, it is tried again after a short pause, until
+    /// it succeeds, fails with another error, or the token is cancelled. So a
+    /// flush stuck on a sink that is not accepting data can be interrupted.
+    ///
+    /// In chunked mode, see [`Cancellable::chunked`], `flush` works like this.
+    ///
+    /// Note that a cancelled flush may leave data unflushed in the inner value.
+    pub fn flush_cancellable(&mut self) -> io::Result<()> {
+        let start = self.call_start();
+        loop {
+            self.check_call(start)?;
+            match self.timed("flush", |w| w.flush()) {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+                    ) =>
+                {
+                    std::thread::sleep(FLUSH_RETRY_DELAY);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    // See `Cancellable::min_size`. The token has already been checked.
+    pub(crate) fn write_min(&mut self, buf: &[u8], min_size: usize) -> io::Result<usize> {
+        let want = min_size.min(buf.len());
+        let mut total = 0;
+        loop {
+            let len = self.write_len(buf.len() - total);
+            match self.retry("write", |w| w.write(&buf[total..total + len])) {
+                Ok(Ok(0)) => break,
+                Ok(Ok(n)) => {
+                    self.record_size(n);
+                    total += n;
+                    if total >= want {
+                        break;
+                    }
+                }
+                Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+                Ok(Err(_)) | Err(_) if total > 0 => break,
+                Ok(Err(e)) => return self.inner_count(Err(e)),
+                Err(e) => return self.cancelled_write_count(e),
+            }
+            if total > 0 && self.check_token().is_err() {
+                break;
+            }
+        }
+        self.inspected(Ok(total))
+    }
+
+    pub(crate) fn chunked_write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write_all_since(buf, self.call_start())
+    }
+
+    fn write_all_since(&mut self, mut buf: &[u8], start: Option<Instant>) -> io::Result<()> {
+        while !buf.is_empty() {
+            self.check_call(start)?;
+            let len = self.write_len(buf.len());
+            match self.retry("write", |w| w.write(&buf[..len]))? {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ))
+                }
+                Ok(n) => {
+                    self.record_size(n);
+                    buf = &buf[n..];
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn chunked_write_fmt(&mut self, fmt: std::fmt::Arguments<'_>) -> io::Result<()> {
+        struct Adapter<'a, T> {
+            w: &'a mut Cancellable<T>,
+            start: Option<Instant>,
+            error: io::Result<()>,
+        }
+
+        impl<T: Write> std::fmt::Write for Adapter<'_, T> {
+            fn write_str(&mut self, s: &str) -> std::fmt::Result {
+                self.w
+                    .write_all_since(s.as_bytes(), self.start)
+                    .map_err(|e| {
+                        self.error = Err(e);
+                        std::fmt::Error
+                    })
+            }
+        }
+
+        let start = self.call_start();
+        let mut adapter = Adapter {
+            w: self,
+            start,
+            error: Ok(()),
+        };
+        match std::fmt::write(&mut adapter, fmt) {
+            Ok(()) => Ok(()),
+            Err(_) => match adapter.error {
+                Err(e) => Err(e),
+                Ok(()) => Err(io::Error::other("formatter error")),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Cancellable, CancellationToken};
+    use std::io::{self, Read, Write};
+
+    // Writes at most 3 bytes at a time, cancelling the token after `cancel_at` bytes.
+    struct Gather {
+        data: Vec<u8>,
+        cancel_at: usize,
+        ct: CancellationToken,
+    }
+
+    impl Write for Gather {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(3);
+            self.data.extend_from_slice(&buf[..n]);
+            if self.data.len() >= self.cancel_at {
+                self.ct.cancel();
+            }
+            Ok(n)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Counts the flushes.
+    #[derive(Default)]
+    struct FlushCounter {
+        data: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl Write for FlushCounter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    // Writes `data` in pieces of 2 bytes, flushing after each one.
+    fn write_pieces<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+        for piece in data.chunks(2) {
+            w.write_all(piece)?;
+            w.flush()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_coalesce_flushes() {
+        let ct = CancellationToken::new();
+        let mut w = Cancellable::new(FlushCounter::default(), ct.clone()).chunked(1);
+        write_pieces(&mut w, b"abcdef").unwrap();
+        assert_eq!(w.get_ref().flushes, 3);
+
+        let mut w = Cancellable::new(FlushCounter::default(), ct.clone()).chunked(1);
+        w.coalesce_flushes(|w| write_pieces(w, b"abcdef")).unwrap();
+        assert_eq!(w.get_ref().data, b"abcdef");
+        assert_eq!(w.get_ref().flushes, 1);
+        // Nested, only the outer one flushes.
+        w.coalesce_flushes(|w| {
+            w.coalesce_flushes(|w| write_pieces(w, b"gh"))?;
+            write_pieces(w, b"ij")
+        })
+        .unwrap();
+        assert_eq!(w.get_ref().flushes, 2);
+        // Back to normal.
+        w.flush().unwrap();
+        assert_eq!(w.get_ref().flushes, 3);
+
+        // Cancelled in the middle, not flushed.
+        let mut w = Cancellable::new(FlushCounter::default(), ct.clone()).chunked(1);
+        let err = w
+            .coalesce_flushes(|w| {
+                write_pieces(w, b"ab")?;
+                ct.cancel();
+                write_pieces(w, b"cd")
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(w.get_ref().data, b"ab");
+        assert_eq!(w.get_ref().flushes, 0);
+    }
+
+    #[test]
+    fn test_write_all_vectored() {
+        let ct = CancellationToken::new();
+        let inner = Gather {
+            data: Vec::new(),
+            cancel_at: usize::MAX,
+            ct: ct.clone(),
+        };
+        let mut w = Cancellable::new(inner, ct);
+        let mut bufs = [
+            io::IoSlice::new(b""),
+            io::IoSlice::new(b"abcd"),
+            io::IoSlice::new(b"ef"),
+            io::IoSlice::new(b"ghijk"),
+        ];
+        w.write_all_vectored(&mut bufs).unwrap();
+        assert_eq!(w.get_ref().data, b"abcdefghijk");
+
+        // Cancelled in the middle, even in graceful mode it is an error.
+        let ct = CancellationToken::new();
+        let inner = Gather {
+            data: Vec::new(),
+            cancel_at: 5,
+            ct: ct.clone(),
+        };
+        let mut w = Cancellable::new(inner, ct).graceful();
+        let mut bufs = [
+            io::IoSlice::new(b"abcd"),
+            io::IoSlice::new(b"ef"),
+            io::IoSlice::new(b"ghijk"),
+        ];
+        let err = w.write_all_vectored(&mut bufs).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(w.get_ref().data, b"abcdef");
+    }
+
+    // Cancels the token in the n-th read.
+    struct CancelOnRead(&'static [u8], usize, CancellationToken);
+
+    impl Read for CancelOnRead {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.1 -= 1;
+            if self.1 == 0 {
+                self.2.cancel();
+            }
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_read_to_string() {
+        for own_loop in [false, true] {
+            // A character split between two validation chunks.
+            let mut data = "a".repeat(crate::DEFAULT_CHUNK_SIZE - 1);
+            data.push_str("€uro");
+            let mut s = String::from(">");
+            let mut r = Cancellable::new(data.as_bytes(), CancellationToken::new());
+            if own_loop {
+                r = r.non_retryable();
+            }
+            assert_eq!(r.read_to_string(&mut s).unwrap(), data.len());
+            assert_eq!(s[1..], data);
+
+            let mut s = String::from(">");
+            let mut r = Cancellable::new(&b"ab\xffcd"[..], CancellationToken::new());
+            if own_loop {
+                r = r.non_retryable();
+            }
+            let err = r.read_to_string(&mut s).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(s, ">");
+        }
+
+        // Cancelled while reading, what was read before is kept.
+        let mut s = String::from(">");
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(CancelOnRead(b"abcdef", 2, ct.clone()), ct).chunked(2);
+        let err = r.read_to_string(&mut s).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(s, ">abcd");
+
+        // Cancelled with a character split between two chunks, the part of
+        // it already read is dropped.
+        let mut s = String::from(">");
+        let ct = CancellationToken::new();
+        let data = "ab€".as_bytes();
+        let mut r = Cancellable::new(CancelOnRead(data, 1, ct.clone()), ct).chunked(3);
+        let err = r.read_to_string(&mut s).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(s, ">ab");
+
+        // By default it is delegated, and the cancellation is seen later.
+        let mut s = String::from(">");
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(CancelOnRead(b"abcdef", 2, ct.clone()), ct);
+        assert_eq!(r.read_to_string(&mut s).unwrap(), 6);
+        assert_eq!(s, ">abcdef");
+        assert!(r.read_to_string(&mut s).is_err());
+
+        // An inner error keeps the valid data read before it, as in std.
+        for chunk in [None, Some(2)] {
+            let mut s = String::from(">");
+            let mut r = Cancellable::new((&b"abc"[..]).chain(Failing), CancellationToken::new());
+            if let Some(size) = chunk {
+                r = r.chunked(size);
+            }
+            let err = r.read_to_string(&mut s).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            assert_eq!(s, ">abc");
+
+            let mut s = String::from(">");
+            let mut r = Cancellable::new((&b"ab\xff"[..]).chain(Failing), CancellationToken::new());
+            if let Some(size) = chunk {
+                r = r.chunked(size);
+            }
+            let err = r.read_to_string(&mut s).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            assert_eq!(s, ">");
+        }
+    }
+
+    // A reader that always fails.
+    struct Failing;
+
+    impl Read for Failing {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::ConnectionReset.into())
+        }
+    }
+
+    #[test]
+    fn test_chunk_buffer() {
+        let data = [1; 100];
+        let mut r = Cancellable::with_buffer(&data[..], CancellationToken::new(), 16).chunked(8);
+        let ptr = r.scratch.as_ptr();
+        let mut out = Vec::new();
+        assert_eq!(r.read_to_end(&mut out).unwrap(), 100);
+        assert_eq!(out, data);
+        *r.get_mut() = &data[..10];
+        assert_eq!(r.read_to_end(&mut out).unwrap(), 10);
+        assert_eq!(r.scratch.as_ptr(), ptr);
+        assert_eq!(r.scratch.capacity(), 16);
+
+        r.set_chunk_buffer_size(0);
+        assert_eq!(r.scratch.capacity(), 0);
+        *r.get_mut() = &data[..10];
+        assert_eq!(r.read_to_end(&mut out).unwrap(), 10);
+        assert_eq!(r.scratch.len(), 8);
+    }
+}
@@ -0,0 +1,138 @@
+/// The error inside the `std::io::Error` returned by a cancelled operation.
+///
+/// The outer `std::io::Error` has kind `ErrorKind::BrokenPipe`, or the one
+/// given to [`CancellationToken::cancel_with_error`], but an inner value may
+/// fail with that same kind, so to tell a cancellation apart use [`classify`],
+/// or look for this type with `std::io::Error::get_ref`.
+///
+/// [`CancellationToken::cancel_with_error`]: crate::CancellationToken::cancel_with_error
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CancellationError {
+    reason: Option<String>,
+    #[cfg(all(feature = "debug-backtrace", debug_assertions))]
+    backtrace: CapturedBacktrace,
+}
+
+// A backtrace that does not take part in the comparisons.
+#[cfg(all(feature = "debug-backtrace", debug_assertions))]
+#[derive(Clone, Debug)]
+struct CapturedBacktrace(std::sync::Arc<std::backtrace::Backtrace>);
+
+#[cfg(all(feature = "debug-backtrace", debug_assertions))]
+impl PartialEq for CapturedBacktrace {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+#[cfg(all(feature = "debug-backtrace", debug_assertions))]
+impl Eq for CapturedBacktrace {}
+
+impl CancellationError {
+    pub(crate) fn new(reason: Option<String>) -> Self {
+        CancellationError {
+            reason,
+            #[cfg(all(feature = "debug-backtrace", debug_assertions))]
+            backtrace: CapturedBacktrace(std::sync::Arc::new(
+                std::backtrace::Backtrace::force_capture(),
+            )),
+        }
+    }
+    /// Gets the backtrace of the place where the cancellation was noticed.
+    ///
+    /// Only available with the `debug-backtrace` feature, and in debug builds.
+    /// It is captured even if `RUST_BACKTRACE` is not set.
+    #[cfg(all(feature = "debug-backtrace", debug_assertions))]
+    pub fn backtrace(&self) -> &std::backtrace::Backtrace {
+        &self.backtrace.0
+    }
+    /// Gets the reason given when the token was cancelled, if any.
+    ///
+    /// See [`CancellationToken::cancel_with_reason`](crate::CancellationToken::cancel_with_reason).
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+}
+
+impl std::fmt::Display for CancellationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.reason {
+            None => write!(f, "operation cancelled"),
+            Some(reason) => write!(f, "operation cancelled: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for CancellationError {}
+
+/// What made an operation fail, see [`classify`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The operation was cancelled, maybe with a reason.
+    Cancelled(Option<String>),
+    /// Any other IO error.
+    Io,
+}
+
+/// Tells whether an error comes from a cancellation or from an actual IO failure.
+///
+/// This is usually called at the top of a chain of functions that propagate
+/// `std::io::Error` with `?`.
+pub fn classify(err: &std::io::Error) -> Outcome {
+    match err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<CancellationError>())
+    {
+        Some(e) => Outcome::Cancelled(e.reason.clone()),
+        None => Outcome::Io,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Cancellable, CancellationToken};
+    use std::io::{self, Read};
+
+    #[test]
+    fn test_classify() {
+        let err = io::Error::from(io::ErrorKind::BrokenPipe);
+        assert_eq!(classify(&err), Outcome::Io);
+        let err = io::Error::other("failed");
+        assert_eq!(classify(&err), Outcome::Io);
+
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(io::empty(), ct.clone());
+        ct.cancel();
+        let err = r.read(&mut [0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(classify(&err), Outcome::Cancelled(None));
+        assert_eq!(err.to_string(), "operation cancelled");
+
+        let ct = CancellationToken::new();
+        ct.cancel_with_reason("shutting down");
+        let err = ct.check().unwrap_err();
+        assert_eq!(
+            classify(&err),
+            Outcome::Cancelled(Some("shutting down".to_string()))
+        );
+        assert_eq!(err.to_string(), "operation cancelled: shutting down");
+    }
+
+    #[cfg(all(feature = "debug-backtrace", debug_assertions))]
+    #[test]
+    fn test_backtrace() {
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(io::empty(), ct.clone());
+        ct.cancel();
+        let err = r.read(&mut [0]).unwrap_err();
+        let err = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<CancellationError>())
+            .unwrap();
+        assert_eq!(
+            err.backtrace().status(),
+            std::backtrace::BacktraceStatus::Captured
+        );
+    }
+}
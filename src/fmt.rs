@@ -0,0 +1,88 @@
+use crate::CancellationToken;
+
+/// A newtype around any `std::fmt::Write` value, that makes it cancellable.
+///
+/// Since `std::fmt::Write` can only return a plain `std::fmt::Error`, that is
+/// what a cancelled `write_str` returns. The actual cancellation error is
+/// stored in the wrapper and can be retrieved with
+/// [`CancellableFmt::take_error`], to tell it apart from a failure
+/// of the inner value.
+pub struct CancellableFmt<W> {
+    inner: W,
+    token: CancellationToken,
+    error: Option<std::io::Error>,
+}
+
+impl<W> CancellableFmt<W> {
+    /// Wraps a value as `CancellableFmt`.
+    pub fn new(inner: W, token: CancellationToken) -> Self {
+        Self {
+            inner,
+            token,
+            error: None,
+        }
+    }
+    /// Gets the inner token.
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+    /// Takes the error of the last cancelled write.
+    ///
+    /// It returns `None` if no write has been cancelled, or if the inner value
+    /// itself failed.
+    pub fn take_error(&mut self) -> Option<std::io::Error> {
+        self.error.take()
+    }
+    /// Unwraps the inner value.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+    /// Gets a reference to the inner value.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+    /// Gets a mutable reference to the inner value.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+impl<W: std::fmt::Write> std::fmt::Write for CancellableFmt<W> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        if let Err(e) = self.token.check() {
+            self.error = Some(e);
+            return Err(std::fmt::Error);
+        }
+        self.inner.write_str(s)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fmt::{self, Write};
+
+    // Writes two words, cancelling the token in between.
+    struct CancelHalfway(CancellationToken);
+
+    impl fmt::Display for CancelHalfway {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("hello")?;
+            self.0.cancel();
+            f.write_str(" world")
+        }
+    }
+
+    #[test]
+    fn test_fmt() {
+        let ct = CancellationToken::new();
+        let mut w = CancellableFmt::new(String::new(), ct.clone());
+        write!(w, "{}", 42).unwrap();
+        assert!(w.take_error().is_none());
+
+        write!(w, " {}", CancelHalfway(ct)).unwrap_err();
+        let err = w.take_error().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        assert_eq!(w.into_inner(), "42 hello");
+    }
+}
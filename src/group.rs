@@ -0,0 +1,73 @@
+use crate::CancellationToken;
+
+/// A group of child tokens that are cancelled together.
+///
+/// It is created with [`CancellationToken::group`]. Any number of children
+/// can be created with [`TokenGroup::spawn_token`], for example one for each
+/// task spawned while handling a request, and all of them are cancelled with
+/// [`TokenGroup::cancel_all`], or when the group is dropped, or when the
+/// token that created the group is cancelled.
+///
+/// Cancelling one of the children does not affect the others.
+#[derive(Debug)]
+pub struct TokenGroup {
+    token: CancellationToken,
+}
+
+impl CancellationToken {
+    /// Creates a new group of child tokens of this one.
+    ///
+    /// See [`TokenGroup`].
+    pub fn group(&self) -> TokenGroup {
+        TokenGroup {
+            token: self.child(),
+        }
+    }
+}
+
+impl TokenGroup {
+    /// Creates a new token that belongs to this group.
+    pub fn spawn_token(&self) -> CancellationToken {
+        self.token.child()
+    }
+    /// Cancels all the tokens of this group.
+    ///
+    /// Tokens created after this are already cancelled.
+    pub fn cancel_all(&self) {
+        self.token.cancel();
+    }
+}
+
+impl Drop for TokenGroup {
+    fn drop(&mut self) {
+        self.token.cancel();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_group() {
+        let parent = CancellationToken::new();
+        let group = parent.group();
+        let children: Vec<_> = (0..4).map(|_| group.spawn_token()).collect();
+        children[0].cancel();
+        assert!(children[1..].iter().all(|c| c.check().is_ok()));
+        group.cancel_all();
+        assert!(children.iter().all(|c| c.check().is_err()));
+        assert!(group.spawn_token().check().is_err());
+        assert!(parent.check().is_ok());
+
+        let group = parent.group();
+        let child = group.spawn_token();
+        drop(group);
+        assert!(child.check().is_err());
+
+        let group = parent.group();
+        let child = group.spawn_token();
+        parent.cancel();
+        assert!(child.check().is_err());
+    }
+}
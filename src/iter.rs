@@ -0,0 +1,172 @@
+use crate::{Cancellable, CancellationToken};
+use std::io::Read;
+
+/// A newtype around an iterator of `std::io::Result`, that makes it cancellable.
+///
+/// This is meant for streaming parsers that yield `std::io::Result<Record>`.
+/// The token is checked before every item, and once it is cancelled the next
+/// item is the cancellation error, and then the iteration ends, without
+/// calling the inner iterator again.
+pub struct CancellableResultIter<I> {
+    inner: I,
+    token: CancellationToken,
+    done: bool,
+}
+
+impl<I> CancellableResultIter<I> {
+    /// Wraps an iterator as `CancellableResultIter`.
+    pub fn new(inner: I, token: CancellationToken) -> Self {
+        Self {
+            inner,
+            token,
+            done: false,
+        }
+    }
+    /// Gets the inner token.
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+    /// Unwraps the inner iterator.
+    pub fn into_inner(self) -> I {
+        self.inner
+    }
+}
+
+impl<I, T> Iterator for CancellableResultIter<I>
+where
+    I: Iterator<Item = std::io::Result<T>>,
+{
+    type Item = std::io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if let Err(e) = self.token.check() {
+            self.done = true;
+            return Some(Err(e));
+        }
+        self.inner.next()
+    }
+}
+
+impl<I, T> std::iter::FusedIterator for CancellableResultIter<I> where
+    I: std::iter::FusedIterator<Item = std::io::Result<T>>
+{
+}
+
+/// An iterator over the bytes of a `Cancellable`, reading them in chunks.
+///
+/// It is created with [`Cancellable::cancellable_bytes`].
+pub struct CancellableBytes<T> {
+    inner: Cancellable<T>,
+    buf: Box<[u8]>,
+    pos: usize,
+    len: usize,
+}
+
+impl<T> CancellableBytes<T> {
+    /// Unwraps the inner value.
+    ///
+    /// The bytes that were read but not yielded yet are lost.
+    pub fn into_inner(self) -> Cancellable<T> {
+        self.inner
+    }
+}
+
+impl<T: Read> Cancellable<T> {
+    /// Iterates over the bytes of this value, reading them in chunks.
+    ///
+    /// `Read::bytes` already works with a `Cancellable`, but it calls `read` for
+    /// every byte, that checks the token every time and it is slow. This reads
+    /// a chunk of bytes at a time, checking the token only when reading the
+    /// next chunk. So after a cancellation at most one chunk is yielded, and
+    /// then the cancellation error, once for every call to `next`, as
+    /// `Read::bytes` would do.
+    pub fn cancellable_bytes(self) -> CancellableBytes<T> {
+        let size = self.chunk_len(crate::DEFAULT_CHUNK_SIZE);
+        CancellableBytes {
+            inner: self,
+            buf: vec![0; size].into_boxed_slice(),
+            pos: 0,
+            len: 0,
+        }
+    }
+}
+
+impl<T: Read> Iterator for CancellableBytes<T> {
+    type Item = std::io::Result<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pos == self.len {
+            match self.inner.read(&mut self.buf) {
+                Ok(0) => return None,
+                Ok(n) => {
+                    self.pos = 0;
+                    self.len = n;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let b = self.buf[self.pos];
+        self.pos += 1;
+        Some(Ok(b))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{classify, Outcome};
+    use std::io::{self, BufRead};
+
+    #[test]
+    fn test_bytes() {
+        // A plain `Read::bytes` checks the token for every byte.
+        let ct = CancellationToken::new();
+        #[allow(clippy::unbuffered_bytes)]
+        let mut bytes = Cancellable::new(io::repeat(7), ct.clone()).bytes();
+        assert_eq!(bytes.next().unwrap().unwrap(), 7);
+        ct.cancel();
+        assert_eq!(
+            bytes.next().unwrap().unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+
+        let ct = CancellationToken::new();
+        let mut bytes = Cancellable::new(io::repeat(7), ct.clone()).cancellable_bytes();
+        for b in bytes.by_ref().take(100) {
+            assert_eq!(b.unwrap(), 7);
+        }
+        ct.cancel();
+        let good = bytes.by_ref().take_while(Result::is_ok).count();
+        assert!(good < crate::DEFAULT_CHUNK_SIZE);
+        assert!(bytes.next().unwrap().is_err());
+
+        let ct = CancellationToken::new();
+        let bytes = Cancellable::new(&b"abc"[..], ct)
+            .chunked(2)
+            .cancellable_bytes();
+        let all: Vec<_> = bytes.map(Result::unwrap).collect();
+        assert_eq!(all, b"abc");
+    }
+
+    #[test]
+    fn test_result_iter() {
+        let ct = CancellationToken::new();
+        let data = &b"one\ntwo\nthree\nfour\n"[..];
+        let mut records = CancellableResultIter::new(data.lines(), ct.clone());
+        assert_eq!(records.next().unwrap().unwrap(), "one");
+        assert_eq!(records.next().unwrap().unwrap(), "two");
+        ct.cancel_with_reason("stop");
+        let err = records.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(classify(&err), Outcome::Cancelled(Some("stop".to_string())));
+        assert!(records.next().is_none());
+        assert!(records.next().is_none());
+        // The rest of the records are not consumed.
+        let rest: Vec<_> = records.into_inner().map(Result::unwrap).collect();
+        assert_eq!(rest, ["three", "four"]);
+    }
+}
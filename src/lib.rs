@@ -10,9 +10,53 @@
 /// You can use the same `CancellationToken for as many `Cancellable` objects
 /// as you need.
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Condvar, Mutex, Weak,
 };
+use std::time::{Duration, Instant};
+
+mod ack;
+mod builder;
+mod chunked;
+mod error;
+mod fmt;
+mod group;
+mod iter;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod pair;
+mod pause;
+#[cfg(feature = "positioned-io")]
+mod positioned;
+mod registry;
+#[cfg(all(unix, feature = "signal"))]
+mod signal;
+#[cfg(feature = "stream")]
+mod stream;
+mod trace;
+mod typed;
+#[cfg(feature = "crossbeam-channel")]
+mod with_crossbeam;
+#[cfg(feature = "tokio")]
+mod with_tokio;
+
+pub use builder::CancelBuilder;
+pub use error::{classify, CancellationError, Outcome};
+pub use fmt::CancellableFmt;
+pub use group::TokenGroup;
+pub use iter::{CancellableBytes, CancellableResultIter};
+#[cfg(feature = "metrics")]
+pub use metrics::Stats;
+pub use pair::{CancelWatcher, Canceller};
+pub use pause::PauseToken;
+pub use registry::CancellationRegistry;
+#[cfg(feature = "stream")]
+pub use stream::CancelledStream;
+pub use typed::{CancellableReader, CancellableSeeker, CancellableWriter};
+#[cfg(feature = "crossbeam-channel")]
+pub use with_crossbeam::CancellableCrossbeamReceiver;
+#[cfg(feature = "tokio")]
+pub use with_tokio::CancellableReceiver;
 
 /// This type signals a cancellation event.
 ///
@@ -21,238 +65,4459 @@ use std::sync::{
 /// It also implements `Eq`, `Ord` and `Hash`, with some arbitrary ordering,
 /// so that you can use it as a cheap identifier for your interruptible actions.
 /// All clones of the same token will compare equal.
+///
+/// Tokens created with [`CancellationToken::with_key`] are compared by their
+/// key instead, so different tokens with the same key are equal. A keyed token
+/// is never equal to an unkeyed one, and sorts after all of them.
+///
+/// The order is a proper total order, consistent with `Eq`, so tokens can be
+/// used as keys of a `BTreeMap`. But the order of unkeyed tokens depends on
+/// memory addresses, so it is not stable across program runs.
 #[derive(Clone, Default, Debug)]
 pub struct CancellationToken {
+    inner: Arc<TokenInner>,
+}
+
+// The wakers to wake when a token is cancelled.
+#[derive(Default, Debug)]
+struct Wakers {
+    next_slot: u64,
+    // With the slot of the `Cancelled` future that registered it, if any,
+    // so that it can be removed when the future is dropped.
+    list: Vec<(Option<u64>, std::task::Waker)>,
+}
+
+#[derive(Default, Debug)]
+struct TokenInner {
+    // Shared with other code if created with `CancellationToken::from_atomic`.
     cancelled: Arc<AtomicBool>,
+    // Changes to `cancelled` are done with this lock held, and notified with `cond`.
+    state: Mutex<TokenState>,
+    cond: Condvar,
+    // Tokens that have to be cancelled when this one is.
+    dependents: Mutex<Vec<Link>>,
+    // Tokens that this one depends on.
+    sources: Mutex<Vec<Weak<TokenInner>>>,
+    progress: AtomicU64,
+    progress_limit: Option<u64>,
+    key: Option<String>,
+    label: Option<&'static str>,
+    wakers: Mutex<Wakers>,
+    // The channel shared by all the `CancellationToken::watch` receivers.
+    #[cfg(feature = "tokio")]
+    watch: std::sync::OnceLock<(tokio::sync::watch::Sender<bool>, std::task::Waker)>,
+    // The channel shared by all the `CancellableCrossbeamReceiver`s, that is
+    // disconnected by dropping the sender when the token is cancelled.
+    #[cfg(feature = "crossbeam-channel")]
+    crossbeam: Mutex<
+        Option<(
+            crossbeam_channel::Sender<()>,
+            crossbeam_channel::Receiver<()>,
+        )>,
+    >,
+    callbacks: Mutex<Callbacks>,
+    // For tokens created with `CancellationToken::all`, the tokens that must
+    // be cancelled before this one is.
+    all_of: Vec<Arc<TokenInner>>,
+    reason_mapper: Mutex<ReasonMapper>,
+}
+
+// The callbacks registered with `CancellationToken::on_cancel`.
+#[derive(Default)]
+struct Callbacks(Vec<Box<dyn FnOnce() + Send>>);
+
+impl std::fmt::Debug for Callbacks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Callbacks({})", self.0.len())
+    }
+}
+
+// See `CancellationToken::with_reason_mapper`.
+#[derive(Default)]
+struct ReasonMapper(Option<MapFn>);
+
+type MapFn = Box<dyn Fn(Option<String>) -> Option<String> + Send + Sync>;
+
+impl std::fmt::Debug for ReasonMapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ReasonMapper({})", self.0.is_some())
+    }
+}
+
+#[derive(Default, Debug)]
+struct TokenState {
+    cause: Option<CancelCause>,
+    reason: Option<String>,
+    // See `CancellationToken::cancel_with_error`.
+    error_kind: Option<std::io::ErrorKind>,
+    // See `CancellationToken::ack`.
+    acks: usize,
+    cancelled_at: Option<Instant>,
+    // The earliest deadline set with `CancellationToken::cancel_at`.
+    deadline: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Link {
+    token: Weak<TokenInner>,
+    kind: LinkKind,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum LinkKind {
+    // See `CancellationToken::child`.
+    Child,
+    // A child used by this crate, such as the one that stops a watchdog. It
+    // is not cancelled by `CancellationToken::cancel_children`.
+    Internal,
+    // See `CancellationToken::depends_on`.
+    Depends,
+    // See `CancellationToken::all`.
+    All,
+}
+
+/// The result of [`CancellationToken::cancelled_or`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CancelResult {
+    /// The token was cancelled.
+    Cancelled,
+    /// The timeout expired before the token was cancelled.
+    TimedOut,
+}
+
+/// The reason why a token was cancelled.
+///
+/// See [`CancellationToken::cancel_cause`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CancelCause {
+    /// [`CancellationToken::cancel`] was called.
+    Manual,
+    /// The deadline set with [`CancellationToken::cancel_at`] expired.
+    Deadline,
+    /// The parent of a [child token](CancellationToken::child) was cancelled.
+    /// It holds the [id](CancellationToken::id) of the parent.
+    Parent(usize),
+    /// A token this one [depends on](CancellationToken::depends_on) was
+    /// cancelled. It holds the [id](CancellationToken::id) of that token.
+    Linked(usize),
+    /// A [`CancellationGuard`] or a [`TimeoutGuard`] was dropped.
+    GuardDrop,
+    /// A signal registered with `CancellationToken::cancel_on_signal` was
+    /// delivered. It holds the signal number.
+    Signal(i32),
+    /// An inner value set up with [`Cancellable::cancel_on_error`]
+    /// failed. It holds the kind of the error.
+    InnerError(std::io::ErrorKind),
+    /// The condition of a [watchdog](CancellationToken::spawn_watchdog) became true.
+    Watchdog,
+    /// A thread panicked, see [`CancellationToken::install_panic_hook`].
+    Panic,
+}
+
+/// Shows the label of the token, or its id if it has no label, and whether it
+/// is cancelled.
+impl std::fmt::Display for CancellationToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.label() {
+            Some(label) => write!(f, "{label}")?,
+            None => write!(f, "token {:#x}", self.id())?,
+        }
+        if self.inner.cancelled.load(Ordering::Relaxed) {
+            write!(f, " (cancelled)")?;
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for CancellationToken {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.inner.key, &other.inner.key) {
+            (None, None) => Arc::ptr_eq(&self.inner, &other.inner),
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for CancellationToken {}
+
+impl Ord for CancellationToken {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        match (&self.inner.key, &other.inner.key) {
+            (None, None) => Arc::as_ptr(&self.inner).cmp(&Arc::as_ptr(&other.inner)),
+            (Some(a), Some(b)) => a.cmp(b),
+            (None, Some(_)) => std::cmp::Ordering::Less,
+            (Some(_), None) => std::cmp::Ordering::Greater,
+        }
+    }
 }
 
-impl PartialEq for CancellationToken {
-    fn eq(&self, other: &Self) -> bool {
-        Arc::ptr_eq(&self.cancelled, &other.cancelled)
+impl PartialOrd for CancellationToken {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::hash::Hash for CancellationToken {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        match &self.inner.key {
+            None => Arc::as_ptr(&self.inner).hash(state),
+            Some(key) => key.hash(state),
+        }
+    }
+}
+
+impl CancellationToken {
+    /// Creates a new `CancellationToken`, in a non-cancelled state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Creates a new `CancellationToken` with a key.
+    ///
+    /// Keyed tokens compare equal if their keys are equal, even if they are not
+    /// clones of each other, so the key can be used to identify an operation
+    /// across process restarts. Note that only the identity is shared:
+    /// cancelling a token does not cancel other tokens with the same key.
+    pub fn with_key(key: String) -> Self {
+        CancellationToken {
+            inner: Arc::new(TokenInner {
+                key: Some(key),
+                ..TokenInner::default()
+            }),
+        }
+    }
+    /// Creates a new `CancellationToken` with a label.
+    ///
+    /// The label is only for diagnostics: it is shown by `Debug` and
+    /// `Display`, but unlike a key it does not affect the equality.
+    pub fn with_label(label: &'static str) -> Self {
+        CancellationToken {
+            inner: Arc::new(TokenInner {
+                label: Some(label),
+                ..TokenInner::default()
+            }),
+        }
+    }
+    /// Gets the label of this token, if any.
+    pub fn label(&self) -> Option<&'static str> {
+        self.inner.label
+    }
+    /// Creates a new `CancellationToken` with room for `capacity` callbacks.
+    ///
+    /// Registering callbacks with [`CancellationToken::on_cancel`] will not
+    /// allocate until there are more than `capacity` of them. This is useful
+    /// for long-lived tokens that get a callback for every request.
+    pub fn with_capacity(capacity: usize) -> Self {
+        CancellationToken {
+            inner: Arc::new(TokenInner {
+                callbacks: Mutex::new(Callbacks(Vec::with_capacity(capacity))),
+                ..TokenInner::default()
+            }),
+        }
+    }
+    /// Creates a new `CancellationToken` from an existing stop flag.
+    ///
+    /// Both this token and any other code that uses `flag` see the same state:
+    /// storing `true` in `flag` cancels the token, and cancelling the token
+    /// stores `true` in `flag`.
+    ///
+    /// The flag is read and written with `Ordering::Relaxed`, so it cannot be
+    /// used to synchronize other data, use a lock or a separate fence for
+    /// that. Also, storing into `flag` directly is only seen by the operations
+    /// that check the token, such as [`CancellationToken::check`]: it has no
+    /// cause or reason, and it does not wake up waiting threads or futures, nor
+    /// cancel linked tokens. Prefer [`CancellationToken::cancel`] when possible.
+    pub fn from_atomic(flag: Arc<AtomicBool>) -> Self {
+        CancellationToken {
+            inner: Arc::new(TokenInner {
+                cancelled: flag,
+                ..TokenInner::default()
+            }),
+        }
+    }
+    /// Gets the key of this token, if it has one.
+    pub fn key(&self) -> Option<&str> {
+        self.inner.key.as_deref()
+    }
+    /// Creates a new `CancellationToken` that cancels itself after some progress.
+    ///
+    /// The progress is reported by the application by calling
+    /// [`CancellationToken::add_progress`], and it can mean anything, such as
+    /// the number of bytes actually processed. When the total reaches
+    /// `threshold` the token is cancelled.
+    pub fn cancel_after_bytes(threshold: u64) -> Self {
+        CancellationToken {
+            inner: Arc::new(TokenInner {
+                progress_limit: Some(threshold),
+                ..TokenInner::default()
+            }),
+        }
+    }
+    /// Adds `n` to the progress counter of this token.
+    ///
+    /// If the token was created with [`CancellationToken::cancel_after_bytes`]
+    /// and the total progress reaches the threshold, it is cancelled.
+    /// For other tokens it does nothing but count.
+    pub fn add_progress(&self, n: u64) {
+        let total = self
+            .inner
+            .progress
+            .fetch_add(n, Ordering::Relaxed)
+            .saturating_add(n);
+        if self
+            .inner
+            .progress_limit
+            .is_some_and(|limit| total >= limit)
+        {
+            self.cancel();
+        }
+    }
+    /// Creates a new `CancellationToken` that is a child of this one.
+    ///
+    /// The child is cancelled when this token is, but it can also be cancelled
+    /// by itself without affecting its parent.
+    pub fn child(&self) -> CancellationToken {
+        let child = CancellationToken::new();
+        self.link(&child, LinkKind::Child);
+        child
+    }
+    /// Creates a new `CancellationToken` that is a child of `parent`.
+    ///
+    /// It is the same as `parent.child()`, see [`CancellationToken::child`].
+    pub fn with_parent(parent: &CancellationToken) -> Self {
+        parent.child()
+    }
+    /// Creates a new token in the same state as this one, but not linked to it.
+    ///
+    /// If this token is cancelled, the new one is cancelled too, with the same
+    /// cause and reason. From then on they are independent: a cancellation of
+    /// either of them is not propagated to the other one. The new token has no
+    /// key, label, or linked tokens.
+    pub fn tee(&self) -> CancellationToken {
+        let state = self.inner.state.lock().unwrap();
+        CancellationToken {
+            inner: Arc::new(TokenInner {
+                cancelled: Arc::new(AtomicBool::new(
+                    self.inner.cancelled.load(Ordering::Relaxed),
+                )),
+                state: Mutex::new(TokenState {
+                    cause: state.cause,
+                    reason: state.reason.clone(),
+                    error_kind: state.error_kind,
+                    cancelled_at: state.cancelled_at,
+                    ..TokenState::default()
+                }),
+                ..TokenInner::default()
+            }),
+        }
+    }
+    /// Rewrites the reason inherited by this token from the tokens linked to it.
+    ///
+    /// When this token is cancelled because a parent or any other linked token
+    /// is, `f` is called with the reason of that cancellation, and what it
+    /// returns is the reason of this one. This way a child can add context,
+    /// such as which part of the program it controls. A direct cancellation
+    /// of this token is not affected.
+    ///
+    /// The new reason is inherited by the tokens linked to this one.
+    pub fn with_reason_mapper(
+        self,
+        f: impl Fn(Option<String>) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.inner.reason_mapper.lock().unwrap().0 = Some(Box::new(f));
+        self
+    }
+    /// Cancels this token when any thread panics.
+    ///
+    /// A panic hook is installed, that cancels the token with the panic
+    /// message as the reason, and then calls the hook that was installed
+    /// before, so the panic is still reported as usual. That way background
+    /// workers can stop when the main thread panics.
+    ///
+    /// The panic hook is global state: the hook is installed only the first
+    /// time this is called, in the whole process, and all the tokens share it.
+    /// Calling it again for the same token does nothing. If any other code
+    /// replaces the panic hook later with `std::panic::set_hook`, the tokens
+    /// will no longer be cancelled. The hook only holds weak references, so it
+    /// does not keep the tokens alive.
+    pub fn install_panic_hook(&self) {
+        static TOKENS: Mutex<Vec<Weak<TokenInner>>> = Mutex::new(Vec::new());
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+
+        INSTALL.call_once(|| {
+            let prev = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                let tokens: Vec<_> = TOKENS
+                    .lock()
+                    .map(|t| t.iter().filter_map(Weak::upgrade).collect())
+                    .unwrap_or_default();
+                for t in tokens {
+                    t.cancel(CancelCause::Panic, Some(info.to_string()));
+                }
+                prev(info);
+            }));
+        });
+        let mut tokens = TOKENS.lock().unwrap();
+        tokens.retain(|t| t.strong_count() > 0);
+        if !tokens
+            .iter()
+            .any(|t| std::ptr::eq(t.as_ptr(), &*self.inner))
+        {
+            tokens.push(Arc::downgrade(&self.inner));
+        }
+    }
+    /// Cancels all the children of this token, but not the token itself.
+    ///
+    /// Every token created with [`CancellationToken::child`] from this one, and
+    /// so their own children, is cancelled, as if this token had been
+    /// cancelled, but this token keeps running. Children created later are not
+    /// affected, and neither are the watchdogs of this token.
+    pub fn cancel_children(&self) {
+        // Do not hold the lock while cancelling, see `TokenInner::cancel`.
+        let children: Vec<_> = self
+            .inner
+            .dependents
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|d| d.kind == LinkKind::Child)
+            .filter_map(|d| d.token.upgrade())
+            .collect();
+        let cause = self.inner.link_cause(LinkKind::Child);
+        for child in children {
+            child.cancel(cause, None);
+        }
+    }
+    /// Gets an identifier for this token.
+    ///
+    /// All clones of the same token have the same id, and no two live tokens
+    /// share one, but an id may be reused after a token is dropped.
+    pub fn id(&self) -> usize {
+        Arc::as_ptr(&self.inner) as usize
+    }
+    /// Gets the number of strong references to this token, including this one.
+    ///
+    /// This is meant for debugging leaks: a forgotten reference keeps the
+    /// token alive, and anything waiting for it to be dropped. These count:
+    ///
+    /// * Every clone, including the ones inside wrappers such as
+    ///   [`Cancellable`], guards and futures.
+    /// * Every token created with [`CancellationToken::all`] from this one,
+    ///   for as long as it lives.
+    /// * The threads of [`CancellationToken::cancel_at`],
+    ///   [`CancellationToken::cancel_after`] and
+    ///   [`CancellationToken::spawn_watchdog`], until they finish.
+    ///
+    /// Children, and tokens linked with [`CancellationToken::depends_on`], do
+    /// not count.
+    ///
+    /// Only available with the `debug-strong-count` feature.
+    #[cfg(feature = "debug-strong-count")]
+    pub fn strong_count(&self) -> usize {
+        Arc::strong_count(&self.inner)
+    }
+    /// Gets the total progress added to this token.
+    pub fn progress(&self) -> u64 {
+        self.inner.progress.load(Ordering::Relaxed)
+    }
+    /// Signals this token as _cancelled_.
+    ///
+    /// Note that it takes a non-mutable `self`, so you are able to cancel a
+    /// shared token.
+    ///
+    /// Any token that [depends on](CancellationToken::depends_on) this one
+    /// is cancelled too.
+    pub fn cancel(&self) {
+        self.inner.cancel(CancelCause::Manual, None);
+    }
+    /// Signals this token as _cancelled_, giving a reason.
+    ///
+    /// The reason is included in the error returned by the cancelled
+    /// operations, see [`CancellationError`], and it is inherited by the tokens
+    /// that depend on this one.
+    ///
+    /// If the token is already cancelled nothing changes.
+    pub fn cancel_with_reason(&self, reason: impl Into<String>) {
+        self.inner.cancel(CancelCause::Manual, Some(reason.into()));
+    }
+    /// Like [`CancellationToken::cancel_with_reason`], but tells whether this call cancelled it.
+    ///
+    /// The state and the reason are changed together, atomically, so if many
+    /// threads cancel the token at the same time, only one of them wins, and
+    /// its reason is the one recorded. This returns `true` for the winner,
+    /// and `false` if the token was already cancelled.
+    pub fn cancel_and_reason_once(&self, reason: impl Into<String>) -> bool {
+        self.inner.cancel(CancelCause::Manual, Some(reason.into()))
+    }
+    /// Changes the state of this token, only if it is the expected one.
+    ///
+    /// If `expect_cancelled` is `true` and the token is cancelled, it is reset
+    /// to the non-cancelled state, forgetting the cause and the reason. If
+    /// `expect_cancelled` is `false` and the token is not cancelled, it is
+    /// cancelled as with [`CancellationToken::cancel`]. It returns whether the
+    /// state was changed.
+    ///
+    /// This is a compare-and-swap, so if several threads try to reset a token
+    /// at the same time, only one of them succeeds. That way a token can be
+    /// reused for the next operation without races.
+    ///
+    /// Resetting a token does not reset the tokens that were cancelled because
+    /// of it, and the callbacks registered with [`CancellationToken::on_cancel`]
+    /// are not registered again.
+    pub fn reset_if(&self, expect_cancelled: bool) -> bool {
+        if !expect_cancelled {
+            return self.inner.cancel(CancelCause::Manual, None);
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        let reset = self
+            .inner
+            .cancelled
+            .compare_exchange(true, false, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok();
+        if reset {
+            state.cause = None;
+            state.reason = None;
+            state.error_kind = None;
+            state.cancelled_at = None;
+            // The timers finish when the token is cancelled.
+            state.deadline = None;
+        }
+        reset
+    }
+    /// Signals this token as _cancelled_, with the error to return.
+    ///
+    /// The cancelled operations fail with an error of the same kind as `err`,
+    /// instead of `ErrorKind::BrokenPipe`, and its message as the reason. The
+    /// error still wraps a [`CancellationError`], so [`classify`] tells it is
+    /// a cancellation. The kind and the reason are inherited by the tokens
+    /// that depend on this one.
+    ///
+    /// `ErrorKind::Interrupted` is not used, because it would be retried, see
+    /// [`Cancellable::non_retryable`]: `ErrorKind::BrokenPipe` is used instead.
+    ///
+    /// If the token is already cancelled nothing changes.
+    pub fn cancel_with_error(&self, err: std::io::Error) {
+        let kind = match err.kind() {
+            std::io::ErrorKind::Interrupted => std::io::ErrorKind::BrokenPipe,
+            kind => kind,
+        };
+        self.inner
+            .cancel_with_kind(CancelCause::Manual, Some(err.to_string()), Some(kind));
+    }
+    /// Gets the current state of this token, as plain data.
+    ///
+    /// All the fields are read at once, so they are consistent with each
+    /// other. This is handy for logging or for status reports.
+    pub fn snapshot(&self) -> TokenSnapshot {
+        let state = self.inner.state.lock().unwrap();
+        TokenSnapshot {
+            cancelled: self.inner.cancelled.load(Ordering::Relaxed),
+            id: self.id(),
+            reason: state.reason.clone(),
+            cancelled_at: state.cancelled_at,
+        }
+    }
+    /// Gets the reason of the cancellation of this token, if any.
+    pub fn reason(&self) -> Option<String> {
+        self.inner.state.lock().unwrap().reason.clone()
+    }
+    /// Cancels this token when `deadline` is reached.
+    ///
+    /// A background thread waits until then, or until the token is cancelled
+    /// by any other means, keeping the token alive all that time.
+    pub fn cancel_at(&self, deadline: Instant) {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            if !self.inner.cancelled.load(Ordering::Relaxed) {
+                state.deadline = Some(state.deadline.map_or(deadline, |d| d.min(deadline)));
+            }
+        }
+        let inner = Arc::clone(&self.inner);
+        std::thread::spawn(move || {
+            let mut state = inner.state.lock().unwrap();
+            while !inner.cancelled.load(Ordering::Relaxed) {
+                let now = Instant::now();
+                if now >= deadline {
+                    drop(state);
+                    inner.cancel(CancelCause::Deadline, None);
+                    return;
+                }
+                state = inner.cond.wait_timeout(state, deadline - now).unwrap().0;
+            }
+        });
+    }
+    /// Gets the deadline of this token, if any.
+    ///
+    /// It is the earliest deadline set with [`CancellationToken::cancel_at`]
+    /// or [`CancellationToken::cancel_after`], even if it has already been
+    /// reached. This is useful to show the time remaining.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.inner.state.lock().unwrap().deadline
+    }
+    /// Blocks the current thread until this token is cancelled.
+    ///
+    /// The thread is parked, it does not spin, and it is woken up as soon as
+    /// the token is cancelled. If it is already cancelled it returns
+    /// immediately.
+    pub fn wait(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        while !self.inner.cancelled.load(Ordering::Relaxed) {
+            state = self.inner.cond.wait(state).unwrap();
+        }
+    }
+    /// Like [`CancellationToken::wait`], but for at most `timeout`.
+    ///
+    /// It returns `true` if the token is cancelled, or `false` if the timeout
+    /// elapsed first.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.inner.state.lock().unwrap();
+        while !self.inner.cancelled.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self
+                .inner
+                .cond
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+    /// Waits until this token is cancelled, for at most `timeout`.
+    ///
+    /// If the token is already cancelled it returns immediately. See
+    /// [`CancellationToken::wait_timeout`].
+    pub fn cancelled_or(&self, timeout: Duration) -> CancelResult {
+        if self.wait_timeout(timeout) {
+            CancelResult::Cancelled
+        } else {
+            CancelResult::TimedOut
+        }
+    }
+    /// Cancels this token after `timeout` has elapsed.
+    ///
+    /// See [`CancellationToken::cancel_at`].
+    pub fn cancel_after(&self, timeout: Duration) {
+        self.cancel_at(Instant::now() + timeout);
+    }
+    /// Creates a new token that is cancelled after `timeout` or when the guard is dropped.
+    ///
+    /// This is for running something for at most `timeout`, and also stopping
+    /// it if the scope exits. Dropping the guard before the deadline finishes
+    /// the background thread of the timer.
+    ///
+    /// See [`TimeoutGuard`].
+    pub fn timeout_guard(timeout: Duration) -> TimeoutGuard {
+        let token = CancellationToken::new();
+        token.cancel_after(timeout);
+        TimeoutGuard(token)
+    }
+    /// Cancels this token when a custom condition becomes true.
+    ///
+    /// A background thread calls `poll` every `interval`, and when it returns
+    /// `true` the token is cancelled. This is useful for conditions that are
+    /// not a deadline, such as a file appearing.
+    ///
+    /// The thread finishes when the token is cancelled by any means, or when
+    /// the watchdog is stopped with [`WatchdogHandle::stop`]. Dropping the
+    /// handle does not stop it. Until then it keeps the token alive.
+    pub fn spawn_watchdog(
+        &self,
+        poll: impl Fn() -> bool + Send + 'static,
+        interval: Duration,
+    ) -> WatchdogHandle {
+        let token = self.clone();
+        // Cancelled to stop the watchdog, and also when the token is.
+        let stop = CancellationToken::new();
+        self.link(&stop, LinkKind::Internal);
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || loop {
+                if stop.check().is_err() {
+                    return;
+                }
+                if poll() {
+                    token.inner.cancel(CancelCause::Watchdog, None);
+                    return;
+                }
+                let state = stop.inner.state.lock().unwrap();
+                if !stop.inner.cancelled.load(Ordering::Relaxed) {
+                    drop(stop.inner.cond.wait_timeout(state, interval).unwrap());
+                }
+            }
+        });
+        WatchdogHandle { stop, thread }
+    }
+    /// Spawns a thread running `f` with a new child token of this one.
+    ///
+    /// Dropping the returned handle cancels the child token and waits for the
+    /// thread to finish, so `f` should check the token regularly. This ties
+    /// the lifetime of the thread to the scope that owns the handle.
+    ///
+    /// See [`JoinOnCancel`].
+    pub fn scope_spawn<F>(&self, f: F) -> JoinOnCancel
+    where
+        F: FnOnce(CancellationToken) + Send + 'static,
+    {
+        let token = self.child();
+        let thread = std::thread::spawn({
+            let token = token.clone();
+            move || f(token)
+        });
+        JoinOnCancel {
+            token,
+            thread: Some(thread),
+        }
+    }
+    /// Gets the cause of the cancellation of this token.
+    ///
+    /// It returns `None` if the token is not cancelled.
+    pub fn cancel_cause(&self) -> Option<CancelCause> {
+        self.inner.state.lock().unwrap().cause
+    }
+    /// Checks whether a token is cancelled.
+    ///
+    /// It returns `Ok(())` if non-cancelled, `Err(ErrorKind::BrokenPipe)` if cancelled,
+    /// or the kind given to [`CancellationToken::cancel_with_error`].
+    /// The error wraps a [`CancellationError`].
+    pub fn check(&self) -> std::io::Result<()> {
+        let cancelled = self.inner.cancelled.load(Ordering::Relaxed);
+        if cancelled {
+            Err(self.error())
+        } else {
+            Ok(())
+        }
+    }
+    // The error returned by a cancelled operation.
+    fn error(&self) -> std::io::Error {
+        let (reason, error_kind) = {
+            let state = self.inner.state.lock().unwrap();
+            (state.reason.clone(), state.error_kind)
+        };
+        std::io::Error::new(
+            error_kind.unwrap_or(std::io::ErrorKind::BrokenPipe),
+            CancellationError::new(reason),
+        )
+    }
+    /// Converts this token into an opaque raw pointer, for FFI.
+    ///
+    /// The token is leaked, until the pointer is converted back with
+    /// [`CancellationToken::from_raw`]. In between the pointer can be used
+    /// with [`CancellationToken::cancel_raw`], for example by C code holding
+    /// it as an opaque handle.
+    pub fn into_raw(self) -> *const () {
+        Arc::into_raw(self.inner) as *const ()
+    }
+    /// Converts back a raw pointer created by [`CancellationToken::into_raw`].
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw`, and every pointer returned by
+    /// `into_raw` must be converted back at most once. After that the pointer
+    /// must not be used again.
+    pub unsafe fn from_raw(ptr: *const ()) -> CancellationToken {
+        CancellationToken {
+            inner: Arc::from_raw(ptr as *const TokenInner),
+        }
+    }
+    /// Cancels the token behind a raw pointer created by [`CancellationToken::into_raw`].
+    ///
+    /// It does not take ownership of the pointer, and it uses the C ABI, so that
+    /// it can be given as a function pointer to foreign code.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw` and it must not have been converted
+    /// back with `from_raw` yet.
+    pub unsafe extern "C" fn cancel_raw(ptr: *const ()) {
+        let inner = &*(ptr as *const TokenInner);
+        inner.cancel(CancelCause::Manual, None);
+    }
+    /// Polls this token for cancellation.
+    ///
+    /// It returns `Poll::Ready(())` if the token is cancelled. If not, it
+    /// returns `Poll::Pending` and the waker in `cx` will be woken when it is.
+    ///
+    /// This is a building block for writing your own futures. For a simple
+    /// future see [`CancellationToken::cancelled`].
+    pub fn poll_cancelled(&self, cx: &mut std::task::Context<'_>) -> std::task::Poll<()> {
+        self.poll_cancelled_in(cx, None)
+    }
+    // Like `poll_cancelled`, but if `slot` is given the waker is kept in a
+    // slot of its own, replaced by later polls and removed through `WakerSlot`.
+    // Otherwise it stays until the token is cancelled.
+    fn poll_cancelled_in(
+        &self,
+        cx: &mut std::task::Context<'_>,
+        slot: Option<&mut Option<u64>>,
+    ) -> std::task::Poll<()> {
+        if self.inner.cancelled.load(Ordering::Relaxed) {
+            return std::task::Poll::Ready(());
+        }
+        let mut wakers = self.inner.wakers.lock().unwrap();
+        // Checked again with the lock held, or we could miss the wake up.
+        if self.inner.cancelled.load(Ordering::Relaxed) {
+            return std::task::Poll::Ready(());
+        }
+        let Wakers { next_slot, list } = &mut *wakers;
+        let Some(slot) = slot else {
+            if !list.iter().any(|(_, w)| w.will_wake(cx.waker())) {
+                list.push((None, cx.waker().clone()));
+            }
+            return std::task::Poll::Pending;
+        };
+        let id = *slot.get_or_insert_with(|| {
+            *next_slot += 1;
+            *next_slot
+        });
+        let pos = list.iter().position(|(s, _)| *s == Some(id));
+        // If the same waker is already registered without a slot, it will be
+        // woken anyway, and only once.
+        if list
+            .iter()
+            .any(|(s, w)| s.is_none() && w.will_wake(cx.waker()))
+        {
+            if let Some(i) = pos {
+                list.swap_remove(i);
+            }
+            return std::task::Poll::Pending;
+        }
+        match pos {
+            Some(i) => {
+                if !list[i].1.will_wake(cx.waker()) {
+                    list[i].1 = cx.waker().clone();
+                }
+            }
+            None => list.push((Some(id), cx.waker().clone())),
+        }
+        std::task::Poll::Pending
+    }
+    /// Registers a callback to be called when this token is cancelled.
+    ///
+    /// The callback is called by the thread that cancels the token, so it
+    /// should be quick. If the token is already cancelled it is called
+    /// immediately by this thread.
+    pub fn on_cancel(&self, f: impl FnOnce() + Send + 'static) {
+        let mut callbacks = self.inner.callbacks.lock().unwrap();
+        // Checked with the lock held, or the callback could be lost.
+        if self.inner.cancelled.load(Ordering::Relaxed) {
+            drop(callbacks);
+            f();
+        } else {
+            callbacks.0.push(Box::new(f));
+        }
+    }
+    /// Sends `()` through `sender` when this token is cancelled.
+    ///
+    /// This lets a coordinator that already waits on a channel learn about the
+    /// cancellation. The send is done with `try_send`, so if the channel is
+    /// full or disconnected nothing is sent, and the cancel never blocks.
+    ///
+    /// See [`CancellationToken::on_cancel`].
+    pub fn bool_sender(&self, sender: std::sync::mpsc::SyncSender<()>) {
+        self.on_cancel(move || {
+            let _ = sender.try_send(());
+        });
+    }
+    /// Returns a future that resolves when this token is cancelled.
+    pub fn cancelled(&self) -> Cancelled {
+        Cancelled {
+            token: self.clone(),
+            slot: WakerSlot::default(),
+        }
+    }
+    /// Like [`CancellationToken::cancelled`], but the future is boxed.
+    ///
+    /// This is handy to store it together with other futures, for example in
+    /// a `Vec` of per-task futures.
+    pub fn cancelled_future_boxed(
+        &self,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'static>> {
+        Box::pin(self.cancelled())
+    }
+    /// Makes this token depend on `upstream`.
+    ///
+    /// From now on, cancelling `upstream` will also cancel this token. If
+    /// `upstream` is already cancelled, this token is cancelled immediately.
+    ///
+    /// It can be called many times, with different upstream tokens, and the
+    /// token will be cancelled when any of them is. Cancelling this token
+    /// does not affect `upstream`.
+    ///
+    /// `upstream` only holds a weak reference to this token, so the link does
+    /// not keep it alive.
+    pub fn depends_on(&self, upstream: &CancellationToken) {
+        upstream.link(self, LinkKind::Depends);
+    }
+    /// Picks the first available token from a list of optional ones.
+    ///
+    /// This avoids juggling `Option`s when a token may come from different
+    /// places, for example a per-request token with a fallback to a global one.
+    /// If none is available, a new non-cancelled token is returned.
+    pub fn first_of(tokens: impl IntoIterator<Item = Option<CancellationToken>>) -> Self {
+        tokens.into_iter().flatten().next().unwrap_or_default()
+    }
+    /// Creates a new `CancellationToken` that depends on all of `tokens`.
+    ///
+    /// The new token is cancelled as soon as any of them is.
+    pub fn any<'a>(tokens: impl IntoIterator<Item = &'a CancellationToken>) -> Self {
+        let merged = CancellationToken::new();
+        for t in tokens {
+            merged.depends_on(t);
+        }
+        merged
+    }
+    /// Merges this token and `others` into a guard that lives and dies with them.
+    ///
+    /// The token of the guard is cancelled as soon as any of them is, as with
+    /// [`CancellationToken::any`], and when it is cancelled, or the guard is
+    /// dropped, all of them are cancelled too. This is useful to tie some
+    /// operations together for the duration of a scope.
+    pub fn merge_into_guard(&self, others: &[CancellationToken]) -> CancellationGuard {
+        let merged = CancellationToken::any(std::iter::once(self).chain(others));
+        for t in std::iter::once(self).chain(others) {
+            t.depends_on(&merged);
+        }
+        CancellationGuard(merged)
+    }
+    /// Creates a new `CancellationToken` that is cancelled when all of `tokens` are.
+    ///
+    /// This is the opposite of [`CancellationToken::any`]: the new token is
+    /// cancelled only after every one of `tokens` is cancelled, for example to
+    /// stop only when all the upstreams are done. Like `Iterator::all`, if
+    /// `tokens` is empty the new token is already cancelled.
+    ///
+    /// The new token keeps `tokens` alive, because a dropped token could never
+    /// be cancelled.
+    pub fn all<'a>(tokens: impl IntoIterator<Item = &'a CancellationToken>) -> Self {
+        let tokens: Vec<_> = tokens.into_iter().collect();
+        let merged = CancellationToken {
+            inner: Arc::new(TokenInner {
+                all_of: tokens.iter().map(|t| t.inner.clone()).collect(),
+                ..TokenInner::default()
+            }),
+        };
+        for t in &tokens {
+            t.link(&merged, LinkKind::All);
+        }
+        if tokens.is_empty() {
+            merged.cancel();
+        }
+        merged
+    }
+    /// Creates a new `CancellationToken` that depends on all of `tokens`, for a while.
+    ///
+    /// Like [`CancellationToken::any`], but the links to the source tokens are
+    /// removed when the returned [`LinkGuard`] is dropped. From then on,
+    /// cancelling a source no longer affects the merged token.
+    pub fn merge_all(tokens: &[CancellationToken]) -> (CancellationToken, LinkGuard) {
+        let merged = CancellationToken::any(tokens);
+        let guard = LinkGuard {
+            token: Arc::downgrade(&merged.inner),
+            sources: tokens.iter().map(|t| Arc::downgrade(&t.inner)).collect(),
+        };
+        (merged, guard)
+    }
+    /// Gets the tokens this one depends on that are currently cancelled.
+    ///
+    /// This tells which condition triggered the cancellation of a token created
+    /// with [`CancellationToken::any`], [`CancellationToken::depends_on`] or
+    /// [`CancellationToken::child`]. Tokens that have been dropped are not
+    /// included.
+    pub fn cancelled_sources(&self) -> Vec<CancellationToken> {
+        self.inner
+            .sources
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|inner| CancellationToken { inner })
+            .filter(|t| t.check().is_err())
+            .collect()
+    }
+
+    fn link(&self, dependent: &CancellationToken, kind: LinkKind) {
+        let cancelled = {
+            let mut dependents = self.inner.dependents.lock().unwrap();
+            dependents.retain(|d| d.token.strong_count() > 0);
+            dependents.push(Link {
+                token: Arc::downgrade(&dependent.inner),
+                kind,
+            });
+            self.inner.cancelled.load(Ordering::Relaxed)
+        };
+        {
+            let mut sources = dependent.inner.sources.lock().unwrap();
+            sources.retain(|s| s.strong_count() > 0);
+            sources.push(Arc::downgrade(&self.inner));
+        }
+        if cancelled {
+            let (reason, error_kind) = {
+                let state = self.inner.state.lock().unwrap();
+                (state.reason.clone(), state.error_kind)
+            };
+            dependent
+                .inner
+                .cancel_linked(kind, self.inner.link_cause(kind), reason, error_kind);
+        }
+    }
+}
+
+impl TokenInner {
+    // The cause of cancelling a token linked to this one.
+    fn link_cause(&self, kind: LinkKind) -> CancelCause {
+        let id = self as *const TokenInner as usize;
+        match kind {
+            LinkKind::Child | LinkKind::Internal => CancelCause::Parent(id),
+            LinkKind::Depends | LinkKind::All => CancelCause::Linked(id),
+        }
+    }
+
+    // Cancels this token because a token linked to it with `kind` was cancelled.
+    fn cancel_linked(
+        &self,
+        kind: LinkKind,
+        cause: CancelCause,
+        reason: Option<String>,
+        error_kind: Option<std::io::ErrorKind>,
+    ) {
+        if kind == LinkKind::All {
+            // Checked with the lock held, so that if the last sources are
+            // cancelled concurrently at least one of them sees all the others.
+            let _state = self.state.lock().unwrap();
+            if !self
+                .all_of
+                .iter()
+                .all(|s| s.cancelled.load(Ordering::Relaxed))
+            {
+                return;
+            }
+        }
+        let reason = match &self.reason_mapper.lock().unwrap().0 {
+            Some(f) => f(reason),
+            None => reason,
+        };
+        self.cancel_with_kind(cause, reason, error_kind);
+    }
+
+    // Returns whether the token was cancelled now, that is, it was not before.
+    fn cancel(&self, cause: CancelCause, reason: Option<String>) -> bool {
+        self.cancel_with_kind(cause, reason, None)
+    }
+
+    // Like `cancel`, with the kind of the error returned by the cancelled operations.
+    fn cancel_with_kind(
+        &self,
+        cause: CancelCause,
+        reason: Option<String>,
+        error_kind: Option<std::io::ErrorKind>,
+    ) -> bool {
+        {
+            let mut state = self.state.lock().unwrap();
+            if self.cancelled.load(Ordering::Relaxed) {
+                return false;
+            }
+            state.cause = Some(cause);
+            state.reason = reason.clone();
+            state.error_kind = error_kind;
+            state.cancelled_at = Some(Instant::now());
+            self.cancelled.store(true, Ordering::Relaxed);
+        }
+        self.cond.notify_all();
+        let wakers = std::mem::take(&mut self.wakers.lock().unwrap().list);
+        for (_, w) in wakers {
+            w.wake();
+        }
+        // Drained instead of taken, to keep the allocated storage.
+        let callbacks: Vec<_> = self.callbacks.lock().unwrap().0.drain(..).collect();
+        for f in callbacks {
+            f();
+        }
+        // Do not hold the lock while cancelling other tokens, there may be cycles.
+        let dependents: Vec<_> = self
+            .dependents
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|d| Some((d.token.upgrade()?, d.kind)))
+            .collect();
+        for (d, kind) in dependents {
+            d.cancel_linked(kind, self.link_cause(kind), reason.clone(), error_kind);
+        }
+        true
+    }
+}
+
+/// The state of a token at some point.
+///
+/// It is created with [`CancellationToken::snapshot`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenSnapshot {
+    /// Whether the token is cancelled.
+    pub cancelled: bool,
+    /// The id of the token, see [`CancellationToken::id`].
+    pub id: usize,
+    /// The reason of the cancellation, if any.
+    pub reason: Option<String>,
+    /// When the token was cancelled.
+    ///
+    /// It is `None` if the token is not cancelled, and also for a token created
+    /// with [`CancellationToken::from_atomic`] that was cancelled by setting
+    /// the flag directly.
+    pub cancelled_at: Option<Instant>,
+}
+
+/// Removes the links of a merged token when dropped.
+///
+/// It is created with [`CancellationToken::merge_all`].
+#[must_use = "dropping the guard removes the links immediately"]
+#[derive(Debug)]
+pub struct LinkGuard {
+    token: Weak<TokenInner>,
+    sources: Vec<Weak<TokenInner>>,
+}
+
+impl Drop for LinkGuard {
+    fn drop(&mut self) {
+        for source in self.sources.iter().filter_map(Weak::upgrade) {
+            source
+                .dependents
+                .lock()
+                .unwrap()
+                .retain(|d| !d.token.ptr_eq(&self.token));
+        }
+        if let Some(token) = self.token.upgrade() {
+            token
+                .sources
+                .lock()
+                .unwrap()
+                .retain(|s| !self.sources.iter().any(|x| x.ptr_eq(s)));
+        }
+    }
+}
+
+/// A watchdog started with [`CancellationToken::spawn_watchdog`].
+#[derive(Debug)]
+pub struct WatchdogHandle {
+    stop: CancellationToken,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl WatchdogHandle {
+    /// Stops the watchdog, without cancelling the token.
+    ///
+    /// It waits for the background thread to finish, so if `poll` is running
+    /// it waits for it to return.
+    pub fn stop(self) {
+        self.stop.cancel();
+        // A panic in `poll` is not our business.
+        let _ = self.thread.join();
+    }
+}
+
+/// A token that is cancelled after a timeout or when dropped.
+///
+/// It is created with [`CancellationToken::timeout_guard`]. The cause of the
+/// cancellation is [`CancelCause::Deadline`] if the timeout expired first, or
+/// [`CancelCause::GuardDrop`] if the guard was dropped first.
+#[derive(Debug)]
+pub struct TimeoutGuard(CancellationToken);
+
+impl TimeoutGuard {
+    /// Gets the token of this guard.
+    ///
+    /// You will probably need to clone it, to pass it to the operations that
+    /// run while the guard is alive.
+    pub fn token(&self) -> &CancellationToken {
+        &self.0
+    }
+}
+
+impl Drop for TimeoutGuard {
+    fn drop(&mut self) {
+        self.0.inner.cancel(CancelCause::GuardDrop, None);
+    }
+}
+
+/// A thread started with [`CancellationToken::scope_spawn`].
+///
+/// When dropped it cancels the token of the thread and joins it. A panic of
+/// the thread is ignored then, use [`JoinOnCancel::join`] to get it.
+#[derive(Debug)]
+pub struct JoinOnCancel {
+    token: CancellationToken,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl JoinOnCancel {
+    /// Gets the token of the thread.
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+    /// Cancels the token of the thread and joins it.
+    ///
+    /// It returns the panic of the thread, if any.
+    pub fn join(mut self) -> std::thread::Result<()> {
+        self.token.cancel();
+        self.thread.take().unwrap().join()
+    }
+}
+
+impl Drop for JoinOnCancel {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.token.cancel();
+            let _ = thread.join();
+        }
+    }
+}
+
+// A waker kept in a slot of its own in a token, see `poll_cancelled_in`.
+// Polling again replaces it, and it is removed by `clear` or on drop. It
+// holds the token weakly, so it does not keep it alive.
+#[derive(Debug, Default)]
+pub(crate) struct WakerSlot(Option<(Weak<TokenInner>, u64)>);
+
+impl WakerSlot {
+    // Like `CancellationToken::poll_cancelled`, with the waker in this slot.
+    pub(crate) fn poll(
+        &mut self,
+        token: &CancellationToken,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<()> {
+        if let Some((inner, _)) = &self.0 {
+            // The owner may have swapped tokens since the last poll.
+            if !std::ptr::eq(inner.as_ptr(), Arc::as_ptr(&token.inner)) {
+                self.clear();
+            }
+        }
+        let mut id = self.0.as_ref().map(|(_, id)| *id);
+        let res = token.poll_cancelled_in(cx, Some(&mut id));
+        if let (None, Some(id)) = (&self.0, id) {
+            self.0 = Some((Arc::downgrade(&token.inner), id));
+        }
+        res
+    }
+    // Removes the waker, if any.
+    pub(crate) fn clear(&mut self) {
+        if let Some((inner, id)) = self.0.take() {
+            if let Some(inner) = inner.upgrade() {
+                let mut wakers = inner.wakers.lock().unwrap();
+                wakers.list.retain(|(s, _)| *s != Some(id));
+            }
+        }
+    }
+}
+
+impl Drop for WakerSlot {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// A future that resolves when a token is cancelled.
+///
+/// It is created with [`CancellationToken::cancelled`]. Its waker is removed
+/// from the token when it is dropped, so many short-lived futures of a
+/// long-lived token do not pile up.
+#[derive(Debug)]
+pub struct Cancelled {
+    token: CancellationToken,
+    slot: WakerSlot,
+}
+
+impl std::future::Future for Cancelled {
+    type Output = ();
+
+    fn poll(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<()> {
+        let this = self.get_mut();
+        this.slot.poll(&this.token, cx)
+    }
+}
+
+/// A newtype around `CancellationToken` that automatically cancels on `drop`.
+pub struct CancellationGuard(pub CancellationToken);
+
+impl Drop for CancellationGuard {
+    fn drop(&mut self) {
+        self.0.inner.cancel(CancelCause::GuardDrop, None);
+    }
+}
+
+/// A newtype around any `Read`, `Write` or `Seek` value, that makes it cancellable.
+pub struct Cancellable<T> {
+    inner: T,
+    token: CancellationToken,
+    split_vectored: bool,
+    fuse: bool,
+    fused: AtomicBool,
+    graceful: bool,
+    eof_on_cancel: bool,
+    last_error: Option<std::io::Error>,
+    // `None` until one of the rarely used options is set, see `Options`.
+    options: Option<Box<Options>>,
+    last_aborted: AtomicBool,
+    // See `Cancellable::abort_offset`.
+    transferred: AtomicU64,
+    abort_offset: AtomicU64,
+    ops_left: Option<AtomicU64>,
+    // Reused by the chunked operations that need a buffer of their own.
+    scratch: Vec<u8>,
+    min_size: Option<usize>,
+    max_write: Option<usize>,
+    // Inside `coalesce_flushes`, whether a flush has been deferred.
+    deferred_flush: Option<bool>,
+    rewind: Option<(u64, SeekFn<T>)>,
+    chunk_size: Option<usize>,
+    // The waker of a pending async operation.
+    #[cfg(feature = "tokio")]
+    waker: WakerSlot,
+}
+
+// The options of a `Cancellable` that are rarely used. They are kept apart,
+// so that without them an operation only checks that there are none, besides
+// the token.
+struct Options {
+    retry: Option<RetryPolicy>,
+    spin: Option<Duration>,
+    delay: Option<Duration>,
+    warn: Option<(Duration, WarnFn)>,
+    inspect: Option<InspectFn>,
+    on_abort: Option<AbortFn>,
+    call_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    cancel_on_error: bool,
+    non_retryable: bool,
+    // The interval and the base for `last_check`, that is in nanoseconds.
+    check_every: Option<(Duration, Instant)>,
+    last_check: AtomicU64,
+    #[cfg(feature = "metrics")]
+    metrics: metrics::Metrics,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            retry: None,
+            spin: None,
+            delay: None,
+            warn: None,
+            inspect: None,
+            on_abort: None,
+            call_timeout: None,
+            deadline: None,
+            cancel_on_error: false,
+            non_retryable: false,
+            check_every: None,
+            last_check: AtomicU64::new(NEVER_CHECKED),
+            #[cfg(feature = "metrics")]
+            metrics: metrics::Metrics::default(),
+        }
+    }
+}
+
+impl Options {
+    // Whether the token was checked recently enough, see `checkpoint_every`.
+    fn skip_check(&self) -> bool {
+        let Some((interval, base)) = self.check_every else {
+            return false;
+        };
+        let now = base.elapsed().as_nanos() as u64;
+        let last = self.last_check.load(Ordering::Relaxed);
+        if last != NEVER_CHECKED && now.saturating_sub(last) < interval.as_nanos() as u64 {
+            return true;
+        }
+        self.last_check.store(now, Ordering::Relaxed);
+        false
+    }
+}
+
+type WarnFn = Box<dyn Fn(&'static str, Duration) + Send + Sync>;
+type InspectFn = Box<dyn Fn(&std::io::Result<usize>) + Send + Sync>;
+type AbortFn = Box<dyn Fn() + Send + Sync>;
+type SeekFn<T> = fn(&mut T, std::io::SeekFrom) -> std::io::Result<u64>;
+
+/// How a `Cancellable` retries transient errors.
+///
+/// An inner operation that fails with `ErrorKind::Interrupted` or
+/// `ErrorKind::WouldBlock` is retried up to `max_retries` times, sleeping for
+/// `backoff` before each retry. The token is checked before every retry, so
+/// a cancellation stops the retries immediately.
+///
+/// See [`Cancellable::retry_policy`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Maximum number of retries of a single operation.
+    pub max_retries: u32,
+    /// Time to wait before each retry.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            backoff: Duration::from_millis(10),
+        }
+    }
+}
+
+const DEFAULT_CHUNK_SIZE: usize = 8 * 1024;
+const NEVER_CHECKED: u64 = u64::MAX;
+const NEVER_ABORTED: u64 = u64::MAX;
+// How often the token is checked during an `inject_delay` sleep.
+const DELAY_STEP: Duration = Duration::from_millis(1);
+
+// See `Cancellable::cancel_on_error`. It does not borrow the whole
+// `Cancellable`, because `fill_buf` cannot do that.
+fn cancel_on_inner_error(enabled: bool, token: &CancellationToken, err: &std::io::Error) {
+    if enabled && err.kind() != std::io::ErrorKind::Interrupted && classify(err) == Outcome::Io {
+        token
+            .inner
+            .cancel(CancelCause::InnerError(err.kind()), Some(err.to_string()));
+    }
+}
+
+impl<T> Cancellable<T> {
+    /// Wraps a value as `Cancellable`.
+    pub fn new(inner: T, token: CancellationToken) -> Self {
+        Self {
+            inner,
+            token,
+            split_vectored: false,
+            fuse: false,
+            fused: AtomicBool::new(false),
+            graceful: false,
+            eof_on_cancel: false,
+            last_error: None,
+            // The metrics are always collected.
+            #[cfg(feature = "metrics")]
+            options: Some(Box::default()),
+            #[cfg(not(feature = "metrics"))]
+            options: None,
+            last_aborted: AtomicBool::new(false),
+            transferred: AtomicU64::new(0),
+            abort_offset: AtomicU64::new(NEVER_ABORTED),
+            ops_left: None,
+            scratch: Vec::new(),
+            min_size: None,
+            max_write: None,
+            deferred_flush: None,
+            rewind: None,
+            chunk_size: None,
+            #[cfg(feature = "tokio")]
+            waker: WakerSlot::default(),
+        }
+    }
+    /// Wraps a value as `Cancellable`, retrying transient errors.
+    ///
+    /// It is the same as `Cancellable::new(inner, token).retry_policy(policy)`,
+    /// see [`Cancellable::retry_policy`].
+    pub fn with_retry(inner: T, token: CancellationToken, policy: RetryPolicy) -> Self {
+        Self::new(inner, token).retry_policy(policy)
+    }
+    /// Retries transient errors.
+    ///
+    /// The `read`, `write`, `flush` and `fill_buf` operations, and their
+    /// vectored variants, are retried as described in [`RetryPolicy`].
+    /// Operations that may have partially succeeded before failing, such as
+    /// `write_all`, are not retried.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.options_mut().retry = Some(policy);
+        self
+    }
+    /// Wraps a value as `Cancellable`, warning about slow operations.
+    ///
+    /// See [`Cancellable::warn_threshold`].
+    pub fn with_warn_threshold(
+        inner: T,
+        token: CancellationToken,
+        threshold: Duration,
+        warn: impl Fn(&'static str, Duration) + Send + Sync + 'static,
+    ) -> Self {
+        Self::new(inner, token).warn_threshold(threshold, warn)
+    }
+    /// Warns about slow operations.
+    ///
+    /// Every call to the inner value is timed, and if it takes longer than
+    /// `threshold` then `warn` is called with the name of the operation, such
+    /// as `"read"`, and the time it took.
+    ///
+    /// A cancellation cannot interrupt an inner operation that is already
+    /// running, so this helps to find out which operations need to be split
+    /// into smaller ones to reduce the cancellation latency.
+    pub fn warn_threshold(
+        mut self,
+        threshold: Duration,
+        warn: impl Fn(&'static str, Duration) + Send + Sync + 'static,
+    ) -> Self {
+        self.options_mut().warn = Some((threshold, Box::new(warn)));
+        self
+    }
+    /// Wraps a value as `Cancellable`, calling `f` with the result of every operation.
+    ///
+    /// See [`Cancellable::on_result`].
+    pub fn inspect(
+        inner: T,
+        token: CancellationToken,
+        f: impl Fn(&std::io::Result<usize>) + Send + Sync + 'static,
+    ) -> Self {
+        Self::new(inner, token).on_result(f)
+    }
+    /// Calls `f` with the result of every operation.
+    ///
+    /// `f` is called with the result of every `read` and `write`, and their
+    /// vectored variants, just before returning it, including the ones that
+    /// fail because the token is cancelled. The result is not changed.
+    ///
+    /// This is handy for logging or tracing.
+    pub fn on_result(
+        mut self,
+        f: impl Fn(&std::io::Result<usize>) + Send + Sync + 'static,
+    ) -> Self {
+        self.options_mut().inspect = Some(Box::new(f));
+        self
+    }
+    /// Wraps a value as `Cancellable`, calling `f` when an operation is aborted.
+    ///
+    /// See [`Cancellable::on_abort`].
+    pub fn with_on_abort(
+        inner: T,
+        token: CancellationToken,
+        f: impl Fn() + Send + Sync + 'static,
+    ) -> Self {
+        Self::new(inner, token).on_abort(f)
+    }
+    /// Calls `f` when an operation is aborted.
+    ///
+    /// Unlike [`CancellationToken::on_cancel`], `f` is not called when the
+    /// token is cancelled, but every time an operation of this value fails
+    /// because of the cancellation, just before returning the error. It runs
+    /// in the thread doing the operation, so it can tear down this stream.
+    pub fn on_abort(mut self, f: impl Fn() + Send + Sync + 'static) -> Self {
+        self.options_mut().on_abort = Some(Box::new(f));
+        self
+    }
+    /// Wraps a value as `Cancellable`, sleeping for `per_op` before every inner call.
+    ///
+    /// See [`Cancellable::inject_delay`].
+    pub fn with_injected_delay(inner: T, token: CancellationToken, per_op: Duration) -> Self {
+        Self::new(inner, token).inject_delay(per_op)
+    }
+    /// Sleeps for `per_op` before every inner call.
+    ///
+    /// This simulates a slow inner value, to test the cancellation latency
+    /// without real sockets. The sleep checks the token every millisecond,
+    /// and it fails with the cancellation error if it is cancelled, as would
+    /// the following check of the token. Only the `read`, `write`, `flush`
+    /// and `fill_buf` calls, and their vectored variants, are delayed.
+    pub fn inject_delay(mut self, per_op: Duration) -> Self {
+        self.options_mut().delay = Some(per_op);
+        self
+    }
+    /// Wraps a value as `Cancellable`, passing at most `max` bytes to every inner `write`.
+    ///
+    /// See [`Cancellable::max_write`].
+    ///
+    /// # Panics
+    ///
+    /// If `max` is zero.
+    pub fn with_max_write(inner: T, token: CancellationToken, max: usize) -> Self {
+        Self::new(inner, token).max_write(max)
+    }
+    /// Passes at most `max` bytes to every inner `write`.
+    ///
+    /// This is for sinks that reject bigger writes, such as some pipes. A
+    /// `write` of a bigger buffer is split into several inner writes,
+    /// checking the token between them, and it returns the total number of
+    /// bytes written. If the token is cancelled after some bytes have been
+    /// written, that partial count is returned, and the next call fails.
+    ///
+    /// # Panics
+    ///
+    /// If `max` is zero.
+    pub fn max_write(mut self, max: usize) -> Self {
+        assert!(max > 0, "max write size must not be zero");
+        self.max_write = Some(max);
+        self
+    }
+    /// Wraps a value as `Cancellable`, allowing only `max_ops` operations.
+    ///
+    /// See [`Cancellable::op_limit`].
+    pub fn with_op_limit(inner: T, token: CancellationToken, max_ops: u64) -> Self {
+        Self::new(inner, token).op_limit(max_ops)
+    }
+    /// Allows only `max_ops` operations.
+    ///
+    /// Every call to a `Read`, `Write`, `BufRead::fill_buf` or `Seek`
+    /// function counts as one operation, even if it is done in chunks, and
+    /// after `max_ops` of them they fail with a cancellation error, with the
+    /// reason `"operation limit reached"`, whatever the state of the token.
+    ///
+    /// This is useful to test the handling of partial transfers, or as a hard
+    /// limit.
+    pub fn op_limit(mut self, max_ops: u64) -> Self {
+        self.ops_left = Some(AtomicU64::new(max_ops));
+        self
+    }
+    /// Wraps a value as `Cancellable`, with a buffer of `size` bytes for chunked operations.
+    ///
+    /// See [`Cancellable::chunk_buffer`].
+    pub fn with_buffer(inner: T, token: CancellationToken, size: usize) -> Self {
+        Self::new(inner, token).chunk_buffer(size)
+    }
+    /// Uses a buffer of `size` bytes for chunked operations.
+    ///
+    /// Some chunked operations, such as `read_to_end`, need a buffer of their
+    /// own, see [`Cancellable::chunked`]. It is allocated once and reused by
+    /// all of them, so they do not allocate on every call. By default it is
+    /// allocated the first time it is needed, with the chunk size.
+    ///
+    /// The size can be changed later with [`Cancellable::set_chunk_buffer_size`].
+    pub fn chunk_buffer(mut self, size: usize) -> Self {
+        self.set_chunk_buffer_size(size);
+        self
+    }
+    /// Sets the size of the buffer used by chunked operations.
+    ///
+    /// See [`Cancellable::chunk_buffer`]. A size of zero frees the buffer, and
+    /// the default size will be used the next time it is needed.
+    pub fn set_chunk_buffer_size(&mut self, size: usize) {
+        self.scratch.resize(size, 0);
+        self.scratch.shrink_to_fit();
+    }
+    /// Wraps a value as `Cancellable`, with a time budget for each call.
+    ///
+    /// See [`Cancellable::call_timeout`].
+    pub fn with_call_timeout(inner: T, token: CancellationToken, budget: Duration) -> Self {
+        Self::new(inner, token).call_timeout(budget)
+    }
+    /// Sets a time budget for each call.
+    ///
+    /// If a call takes longer than `budget`, it fails with
+    /// `ErrorKind::TimedOut`. This is different from a deadline for the whole
+    /// transfer: each call gets its own budget.
+    ///
+    /// A single inner operation cannot be interrupted, so the budget is
+    /// checked between chunks, see [`Cancellable::chunked`]. If no chunk size
+    /// is set, 8 KiB is used.
+    pub fn call_timeout(mut self, budget: Duration) -> Self {
+        self.chunk_size.get_or_insert(DEFAULT_CHUNK_SIZE);
+        self.options_mut().call_timeout = Some(budget);
+        self
+    }
+    /// Wraps a value as `Cancellable`, with an overall deadline.
+    ///
+    /// See [`Cancellable::deadline`].
+    pub fn with_deadline(inner: T, token: CancellationToken, deadline: Instant) -> Self {
+        Self::new(inner, token).deadline(deadline)
+    }
+    /// Sets an overall deadline.
+    ///
+    /// Every operation checks both the token and the deadline: after the
+    /// deadline they fail with `ErrorKind::TimedOut`, and if the token is
+    /// cancelled with the usual cancellation error.
+    ///
+    /// Unlike [`CancellationToken::cancel_at`] this does not need a
+    /// background thread, and it does not cancel the token.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.options_mut().deadline = Some(deadline);
+        self
+    }
+    /// Wraps a value as `Cancellable`, cancelling the token if the inner value fails.
+    ///
+    /// See [`Cancellable::cancel_on_error`].
+    pub fn cancel_token_on_error(inner: T, token: CancellationToken) -> Self {
+        Self::new(inner, token).cancel_on_error()
+    }
+    /// Cancels the token if the inner value fails.
+    ///
+    /// Any error returned by the inner value, except `ErrorKind::Interrupted`
+    /// and cancellation errors, cancels the token, with the error message as
+    /// the reason. That way, in a fan-out pipeline, the failure of one stream
+    /// stops all the others that share the token.
+    ///
+    /// Beware that transient errors, such as `ErrorKind::WouldBlock` or
+    /// `ErrorKind::TimedOut`, will also cancel the token, even if the
+    /// operation could be retried.
+    pub fn cancel_on_error(mut self) -> Self {
+        self.options_mut().cancel_on_error = true;
+        self
+    }
+    /// Wraps a value as `Cancellable`, using the token of a guard.
+    ///
+    /// The value will be cancelled when the guard is dropped.
+    pub fn from_guard(inner: T, guard: &CancellationGuard) -> Self {
+        Self::new(inner, guard.0.clone())
+    }
+    /// Makes this value _fused_.
+    ///
+    /// A fused `Cancellable` remembers the first cancellation it observes,
+    /// and from then on every operation fails with the cancellation error
+    /// without ever touching the inner value again, even the ones that do not
+    /// usually check the token, such as `BufRead::consume`.
+    ///
+    /// This is useful if the inner value may be left in an inconsistent state
+    /// after an aborted operation.
+    pub fn fuse(mut self) -> Self {
+        self.fuse = true;
+        self
+    }
+    /// Makes this value _graceful_.
+    ///
+    /// In graceful mode a cancelled `read`, `write` or `fill_buf` does not
+    /// fail, but returns `Ok(0)` (or an empty buffer), as if the stream had
+    /// ended, so that simple loops finish cleanly.
+    ///
+    /// Errors from the inner value are also turned into `Ok(0)`, except
+    /// `ErrorKind::Interrupted`, but they are not lost: they are stored and
+    /// can be retrieved later with [`Cancellable::take_error`].
+    ///
+    /// Other operations, such as `read_exact` or `seek`, are not affected.
+    pub fn graceful(mut self) -> Self {
+        self.graceful = true;
+        self
+    }
+    /// Reports cancellation as end of stream for reads, but as an error for
+    /// writes.
+    ///
+    /// With this policy a cancelled `read` returns `Ok(0)` and a cancelled
+    /// `fill_buf` an empty buffer, so a reading loop ends as if the input
+    /// was exhausted. A cancelled `write` or `write_vectored` still returns
+    /// the cancellation error.
+    ///
+    /// The asymmetry is deliberate. A reader that stops early only loses data
+    /// that it did not want anymore, but `Ok(0)` from a write means that
+    /// nothing could be written, and `write_all` turns it into a misleading
+    /// `ErrorKind::WriteZero`. Worse, a writer that hides the cancellation can
+    /// leave a truncated output that looks complete. So writers must see the
+    /// error, and [`classify`] it in the usual way.
+    ///
+    /// Unlike [`Cancellable::graceful`], errors from the inner value are not
+    /// affected, and neither are the operations that fail on a short input,
+    /// such as `read_exact`. If `graceful` is also set, it takes precedence
+    /// and writes return `Ok(0)` too.
+    pub fn read_eof_write_error(mut self) -> Self {
+        self.eof_on_cancel = true;
+        self
+    }
+    /// Takes the last error from the inner value deferred in graceful mode.
+    ///
+    /// It returns `None` if there was no error, or if it was already taken.
+    pub fn take_error(&mut self) -> Option<std::io::Error> {
+        self.last_error.take()
+    }
+    // Runs an inner operation, warning if it is too slow.
+    fn timed<R>(
+        &mut self,
+        name: &'static str,
+        op: impl FnOnce(&mut T) -> std::io::Result<R>,
+    ) -> std::io::Result<R> {
+        let res = Self::time(self.options.as_deref(), name, &mut self.inner, op);
+        if let Err(e) = &res {
+            self.inner_error(e);
+            self.check_interrupted(e)?;
+        }
+        res
+    }
+    // In non-retryable mode, an interrupted inner operation of a cancelled
+    // value fails with the cancellation error.
+    fn check_interrupted(&self, err: &std::io::Error) -> std::io::Result<()> {
+        if self.is_non_retryable() && err.kind() == std::io::ErrorKind::Interrupted {
+            self.check_token()?;
+        }
+        Ok(())
+    }
+    // Called for every error returned by the inner value.
+    fn inner_error(&self, err: &std::io::Error) {
+        let enabled = self.options.as_ref().is_some_and(|o| o.cancel_on_error);
+        cancel_on_inner_error(enabled, &self.token, err);
+    }
+    // Sets up the rarely used options, on first use.
+    fn options_mut(&mut self) -> &mut Options {
+        self.options.get_or_insert_with(Box::default)
+    }
+    fn is_non_retryable(&self) -> bool {
+        self.options.as_ref().is_some_and(|o| o.non_retryable)
+    }
+    // Like `timed`, but borrowing only the fields it needs.
+    fn time<U, R>(
+        options: Option<&Options>,
+        name: &'static str,
+        inner: U,
+        op: impl FnOnce(U) -> R,
+    ) -> R {
+        let Some((threshold, warn)) = options.and_then(|o| o.warn.as_ref()) else {
+            return op(inner);
+        };
+        let start = Instant::now();
+        let res = op(inner);
+        let elapsed = start.elapsed();
+        if elapsed > *threshold {
+            warn(name, elapsed);
+        }
+        res
+    }
+    // Sleeps before an inner operation, see `inject_delay`.
+    fn injected_delay(&self) -> std::io::Result<()> {
+        let Some(delay) = self.options.as_ref().and_then(|o| o.delay) else {
+            return Ok(());
+        };
+        let end = Instant::now() + delay;
+        loop {
+            self.check_token()?;
+            let now = Instant::now();
+            if now >= end {
+                return Ok(());
+            }
+            std::thread::sleep(DELAY_STEP.min(end - now));
+        }
+    }
+    // Runs an inner operation, retrying transient errors if there is a retry policy.
+    // The outer error is the cancellation error, if it happened while retrying.
+    fn retry<R>(
+        &mut self,
+        name: &'static str,
+        mut op: impl FnMut(&mut T) -> std::io::Result<R>,
+    ) -> std::io::Result<std::io::Result<R>> {
+        let (spin, retry) = self
+            .options
+            .as_ref()
+            .map_or((None, None), |o| (o.spin, o.retry));
+        let mut retries = 0;
+        loop {
+            self.injected_delay()?;
+            let res = Self::time(self.options.as_deref(), name, &mut self.inner, &mut op);
+            if let (Err(e), Some(interval)) = (&res, spin) {
+                if e.kind() == std::io::ErrorKind::WouldBlock {
+                    std::thread::sleep(interval);
+                    self.check_token()?;
+                    continue;
+                }
+            }
+            let policy = match (&res, retry) {
+                (Err(e), Some(policy))
+                    if retries < policy.max_retries
+                        && matches!(
+                            e.kind(),
+                            std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock
+                        ) =>
+                {
+                    policy
+                }
+                _ => {
+                    if let Err(e) = &res {
+                        self.inner_error(e);
+                        self.check_interrupted(e)?;
+                    }
+                    return Ok(res);
+                }
+            };
+            retries += 1;
+            std::thread::sleep(policy.backoff);
+            self.check_token()?;
+        }
+    }
+    // The result of a cancelled read that returns a count.
+    fn cancelled_count(&mut self, err: std::io::Error) -> std::io::Result<usize> {
+        let err = self.rewound(err);
+        let res = if self.graceful || self.eof_on_cancel {
+            Ok(0)
+        } else {
+            Err(err)
+        };
+        self.inspected(res)
+    }
+    // The result of a cancelled write that returns a count, see
+    // `Cancellable::read_eof_write_error`.
+    fn cancelled_write_count(&mut self, err: std::io::Error) -> std::io::Result<usize> {
+        let err = self.rewound(err);
+        let res = if self.graceful { Ok(0) } else { Err(err) };
+        self.inspected(res)
+    }
+    // The result of an inner operation that returns a count.
+    fn inner_count(&mut self, res: std::io::Result<usize>) -> std::io::Result<usize> {
+        if let Ok(n) = res {
+            self.record_size(n);
+        }
+        let res = match res {
+            Err(e) if self.graceful && e.kind() != std::io::ErrorKind::Interrupted => {
+                self.last_error = Some(e);
+                Ok(0)
+            }
+            res => res,
+        };
+        self.inspected(res)
+    }
+    // Called for the errors of `Read` and `Write` operations, see
+    // `Cancellable::rewind_on_cancel`. It returns the error to report.
+    fn rewound(&mut self, err: std::io::Error) -> std::io::Error {
+        match self.rewind {
+            Some((pos, seek)) if matches!(classify(&err), Outcome::Cancelled(_)) => {
+                match seek(&mut self.inner, std::io::SeekFrom::Start(pos)) {
+                    Ok(_) => err,
+                    Err(e) => e,
+                }
+            }
+            _ => err,
+        }
+    }
+    // Runs a `Read` or `Write` operation that does not return a count.
+    fn rewinding<R>(
+        &mut self,
+        op: impl FnOnce(&mut Self) -> std::io::Result<R>,
+    ) -> std::io::Result<R> {
+        let res = op(self);
+        res.map_err(|e| self.rewound(e))
+    }
+    // The final result of an operation that returns a count, see `Cancellable::on_result`.
+    fn inspected(&self, res: std::io::Result<usize>) -> std::io::Result<usize> {
+        trace::record_result(&res);
+        if let Some(f) = self.options.as_ref().and_then(|o| o.inspect.as_ref()) {
+            f(&res);
+        }
+        res
+    }
+    // Records the size returned by an inner `read` or `write`.
+    fn record_size(&self, n: usize) {
+        self.record_transferred(n);
+        #[cfg(feature = "metrics")]
+        self.metrics().record_size(n);
+    }
+    // Counts the bytes transferred, see `Cancellable::abort_offset`.
+    fn record_transferred(&self, n: usize) {
+        self.transferred.fetch_add(n as u64, Ordering::Relaxed);
+    }
+    /// Splits the operations into chunks of at most `chunk_size` bytes.
+    ///
+    /// `read` and `write` do not pass more than `chunk_size` bytes to the inner
+    /// value. Operations that would do many of these, such as `read_exact`,
+    /// `read_to_end`, `read_to_string`, `write_all` and `write_fmt`, are done
+    /// as a loop of chunked calls, checking the token between them.
+    ///
+    /// This way a cancellation can stop a big transfer without waiting for
+    /// the whole of it. Also `flush` works like [`Cancellable::flush_cancellable`].
+    ///
+    /// # Panics
+    ///
+    /// If `chunk_size` is zero.
+    pub fn chunked(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk size must not be zero");
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+    /// Checks the token at most once every `interval`.
+    ///
+    /// Operations started less than `interval` after the last check do not
+    /// check the token again. This bounds the cancellation latency by wall
+    /// clock time, while avoiding the check for every small operation.
+    pub fn checkpoint_every(mut self, interval: Duration) -> Self {
+        self.options_mut().check_every = Some((interval, Instant::now()));
+        self
+    }
+    /// Makes `read` and `write` transfer at least `min_size` bytes.
+    ///
+    /// Each `read` or `write` calls the inner value repeatedly, checking the
+    /// token between calls, until at least `min_size` bytes are transferred, or
+    /// the whole buffer if it is smaller. This is useful for protocols that
+    /// need operations of a minimum size, over a source that delivers data in
+    /// small pieces.
+    ///
+    /// If the end of the stream is reached, the inner value fails, or the
+    /// token is cancelled after some bytes have been transferred, the partial
+    /// count is returned, and the next call will return the end of stream or
+    /// fail. The inner value may still transfer less than `min_size` bytes in
+    /// a single call if it is chunked, see [`Cancellable::chunked`].
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = Some(min_size);
+        self
+    }
+    /// Makes sure that a cancellation is never reported as a retryable error.
+    ///
+    /// The cancellation error has kind `ErrorKind::BrokenPipe`, that loops
+    /// such as the ones in `Read::read_to_end` or `Read::read_exact` do not
+    /// retry, they only retry `ErrorKind::Interrupted`. But these loops may
+    /// still spin forever inside the inner value, if it keeps failing with
+    /// `ErrorKind::Interrupted`, without ever looking at the token.
+    ///
+    /// In this mode `read_exact`, `read_to_end`, `read_to_string`,
+    /// `write_all` and `write_fmt` are done as a loop of calls checking the
+    /// token, as in [`Cancellable::chunked`] but without limiting the size of
+    /// each call. And an `ErrorKind::Interrupted` error from the inner value
+    /// is replaced by the cancellation error if the token is cancelled, so
+    /// that a naive retry loop in the caller finishes.
+    ///
+    /// This is recommended if the inner value can be interrupted by signals,
+    /// for example when cancelling with a signal handler.
+    pub fn non_retryable(mut self) -> Self {
+        self.options_mut().non_retryable = true;
+        self
+    }
+    /// Waits for a non-blocking inner value to become ready.
+    ///
+    /// An inner operation that fails with `ErrorKind::WouldBlock` is retried,
+    /// sleeping for `interval` and checking the token before each retry, so
+    /// a non-blocking socket that never becomes ready can still be cancelled.
+    ///
+    /// This is a busy wait: a short `interval` wastes CPU time while the inner
+    /// value is not ready, and a long one adds latency both to the operation
+    /// and to the cancellation. It has no limit of retries, unlike
+    /// [`Cancellable::retry_policy`], that still applies to `Interrupted` errors.
+    pub fn spin_nonblocking(mut self, interval: Duration) -> Self {
+        self.options_mut().spin = Some(interval);
+        self
+    }
+    // Whether the composite operations are done with our own loops.
+    fn own_loops(&self) -> bool {
+        self.chunk_size.is_some() || self.is_non_retryable() || self.max_write.is_some()
+    }
+    /// Makes `write_vectored` write each `IoSlice` individually.
+    ///
+    /// By default `write_vectored` checks the token once and then delegates
+    /// to the inner value. With this mode every slice is written with a
+    /// separate call to `write`, checking the token before each one, so that
+    /// a big scatter-gather write can be interrupted between slices.
+    ///
+    /// If the token is cancelled after some slices have been written, the
+    /// number of bytes written so far is returned, and the next call will
+    /// fail.
+    pub fn split_vectored(mut self) -> Self {
+        self.split_vectored = true;
+        self
+    }
+    // Checks the token at the start of an operation, see `Cancellable::op_limit`.
+    fn check(&self) -> std::io::Result<()> {
+        // Only written if needed, so that threads doing concurrent operations,
+        // such as `ReadAt::read_at`, do not contend for it.
+        if self.last_aborted.load(Ordering::Relaxed) {
+            self.last_aborted.store(false, Ordering::Relaxed);
+        }
+        self.check_token()?;
+        let Some(ops_left) = &self.ops_left else {
+            return Ok(());
+        };
+        match ops_left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1)) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(self.op_limit_reached()),
+        }
+    }
+    // The error of an operation beyond `Cancellable::op_limit`.
+    fn op_limit_reached(&self) -> std::io::Error {
+        self.last_aborted.store(true, Ordering::Relaxed);
+        std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            CancellationError::new(Some("operation limit reached".to_string())),
+        )
+    }
+    // Checks the token, also in the middle of an operation.
+    fn check_token(&self) -> std::io::Result<()> {
+        if self.fused.load(Ordering::Relaxed) {
+            self.aborted();
+            return Err(self.token.error());
+        }
+        let Some(options) = self.options.as_deref() else {
+            return self.token.check().inspect_err(|_| self.token_cancelled());
+        };
+        let res = if options.skip_check() {
+            Ok(())
+        } else {
+            self.token.check()
+        };
+        if res.is_err() {
+            // Once seen, a cancellation is reported always.
+            options.last_check.store(NEVER_CHECKED, Ordering::Relaxed);
+            #[cfg(feature = "metrics")]
+            options.metrics.record_abort();
+            self.token_cancelled();
+        }
+        res?;
+        match options.deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                Err(std::io::ErrorKind::TimedOut.into())
+            }
+            _ => Ok(()),
+        }
+    }
+    // Called when `check_token` finds the token cancelled.
+    fn token_cancelled(&self) {
+        if self.fuse {
+            self.fused.store(true, Ordering::Relaxed);
+        }
+        self.aborted();
+    }
+    // Records that the current operation is aborted, see `last_op_aborted`,
+    // and calls the `on_abort` callback, if any.
+    fn aborted(&self) {
+        self.last_aborted.store(true, Ordering::Relaxed);
+        self.abort_offset
+            .store(self.transferred.load(Ordering::Relaxed), Ordering::Relaxed);
+        if let Some(f) = self.options.as_ref().and_then(|o| o.on_abort.as_ref()) {
+            f();
+        }
+    }
+    /// Gets the inner token.
+    ///
+    /// You will probably need to clone it if you want store it somewhere.
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+    /// Tells whether the last operation was cut short by the cancellation.
+    ///
+    /// This lets a simple loop tell if it ended because of the cancellation
+    /// or because of the end of the stream, without inspecting the errors. It
+    /// is useful specially with [`Cancellable::graceful`], where a cancelled
+    /// `read` looks like the end of the stream.
+    ///
+    /// It is cleared at the start of every operation.
+    pub fn last_op_aborted(&self) -> bool {
+        self.last_aborted.load(Ordering::Relaxed)
+    }
+    /// Gets the number of bytes transferred when an operation was last aborted.
+    ///
+    /// It counts all the bytes read or written through this value, and it is
+    /// recorded when an operation fails, or finishes early, because of the
+    /// cancellation. It is the offset to resume an interrupted transfer from,
+    /// if this value was created at the start of it. For a seekable inner
+    /// value it usually matches `stream_position`, unless it has been moved,
+    /// for example with [`Cancellable::rewind_on_cancel`].
+    ///
+    /// It returns `None` if no operation has been aborted. The bytes of a
+    /// `write_fmt` are not counted, unless it is chunked or non-retryable.
+    pub fn abort_offset(&self) -> Option<u64> {
+        match self.abort_offset.load(Ordering::Relaxed) {
+            NEVER_ABORTED => None,
+            offset => Some(offset),
+        }
+    }
+    /// Replaces the token, returning the old one.
+    ///
+    /// This is useful to reuse a stream, for example from a pool, for a new
+    /// operation with a token of its own. The inner value is not touched, so
+    /// its position is kept, and all the settings are kept too.
+    ///
+    /// A [fused](Cancellable::fuse) value that has seen a cancellation stays
+    /// fused with the new token. Call [`Cancellable::reset_fuse`] if you know
+    /// the inner value is still usable.
+    pub fn replace_token(&mut self, token: CancellationToken) -> CancellationToken {
+        if let Some(options) = &self.options {
+            options.last_check.store(NEVER_CHECKED, Ordering::Relaxed);
+        }
+        std::mem::replace(&mut self.token, token)
+    }
+    /// Forgets the cancellation seen by a [fused](Cancellable::fuse) value.
+    ///
+    /// After this the operations check the token again, instead of failing
+    /// right away. It is only sound if the inner value was not left in an
+    /// inconsistent state by the aborted operation.
+    pub fn reset_fuse(&mut self) {
+        self.fused.store(false, Ordering::Relaxed);
+    }
+    // Replaces the inner value, keeping the token and all the settings,
+    // except the rewind point, because the new value may not be seekable.
+    fn map_inner<U>(self, f: impl FnOnce(T) -> U) -> Cancellable<U> {
+        Cancellable {
+            inner: f(self.inner),
+            token: self.token,
+            split_vectored: self.split_vectored,
+            fuse: self.fuse,
+            fused: self.fused,
+            graceful: self.graceful,
+            eof_on_cancel: self.eof_on_cancel,
+            last_error: self.last_error,
+            options: self.options,
+            last_aborted: self.last_aborted,
+            transferred: self.transferred,
+            abort_offset: self.abort_offset,
+            ops_left: self.ops_left,
+            scratch: self.scratch,
+            min_size: self.min_size,
+            max_write: self.max_write,
+            deferred_flush: self.deferred_flush,
+            rewind: None,
+            chunk_size: self.chunk_size,
+            #[cfg(feature = "tokio")]
+            waker: self.waker,
+        }
+    }
+    /// Unwraps the inner value.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+    /// Gets a reference to the inner value.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+    /// Gets a mutable reference to the inner value.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+    /// Runs a custom operation on the inner value, checking the token first.
+    ///
+    /// This is for operations not covered by the standard traits, such as
+    /// wrapping `&mut T` in the adapter of another library for a single call.
+    /// If the token is cancelled `f` is not called and the cancellation error
+    /// is returned.
+    pub fn with_inner_mut<R>(&mut self, f: impl FnOnce(&mut T) -> R) -> std::io::Result<R> {
+        self.check()?;
+        Ok(f(&mut self.inner))
+    }
+}
+
+impl Cancellable<std::io::Empty> {
+    /// Creates a `Cancellable` reader that is always at the end, see `std::io::empty`.
+    pub fn empty(token: CancellationToken) -> Self {
+        Self::new(std::io::empty(), token)
+    }
+}
+
+impl Cancellable<std::io::Sink> {
+    /// Creates a `Cancellable` writer that discards everything, see `std::io::sink`.
+    pub fn sink(token: CancellationToken) -> Self {
+        Self::new(std::io::sink(), token)
+    }
+}
+
+impl Cancellable<std::io::Repeat> {
+    /// Creates a `Cancellable` reader that yields `byte` forever, see `std::io::repeat`.
+    pub fn repeat(byte: u8, token: CancellationToken) -> Self {
+        Self::new(std::io::repeat(byte), token)
+    }
+}
+
+impl<T: std::io::Write + Send + 'static> Cancellable<T> {
+    /// Converts the inner writer into a boxed trait object.
+    ///
+    /// This is handy to store writers of different types in the same place,
+    /// such as a list of plugin sinks. The token and all the settings are
+    /// kept.
+    pub fn boxed_write(self) -> Cancellable<Box<dyn std::io::Write + Send>> {
+        self.map_inner(|w| Box::new(w) as Box<dyn std::io::Write + Send>)
+    }
+}
+
+impl<T: std::io::Read> Cancellable<T> {
+    /// Limits the number of bytes that can be read from the inner value.
+    ///
+    /// Unlike `Read::take`, which would wrap the `Cancellable` itself, this
+    /// wraps the inner value with `std::io::Take`, so the token and all the
+    /// settings are still available in the returned value.
+    pub fn cancellable_take(self, limit: u64) -> Cancellable<std::io::Take<T>> {
+        self.map_inner(|r| r.take(limit))
+    }
+}
+
+impl<T: std::io::Read> std::io::Read for Cancellable<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let _span = trace::op_span("read");
+        if let Err(e) = self.check() {
+            return self.cancelled_count(e);
+        }
+        if let Some(min_size) = self.min_size {
+            return self.read_min(buf, min_size);
+        }
+        let len = self.chunk_len(buf.len());
+        match self.retry("read", |r| r.read(&mut buf[..len])) {
+            Ok(res) => self.inner_count(res),
+            Err(e) => self.cancelled_count(e),
+        }
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [std::io::IoSliceMut<'_>]) -> std::io::Result<usize> {
+        let _span = trace::op_span("read_vectored");
+        if let Err(e) = self.check() {
+            return self.cancelled_count(e);
+        }
+        match self.retry("read_vectored", |r| r.read_vectored(bufs)) {
+            Ok(res) => self.inner_count(res),
+            Err(e) => self.cancelled_count(e),
+        }
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        self.rewinding(|this| {
+            this.check()?;
+            if this.own_loops() {
+                return this.chunked_read_to_end(buf);
+            }
+            let n = this.timed("read_to_end", |r| r.read_to_end(buf))?;
+            this.record_transferred(n);
+            Ok(n)
+        })
+    }
+
+    // Delegated as `read_to_end`. In our own loop the UTF-8 of a big input is
+    // validated checking the token, and on cancellation the valid characters
+    // read so far are kept.
+    fn read_to_string(&mut self, buf: &mut String) -> std::io::Result<usize> {
+        self.rewinding(|this| {
+            this.check()?;
+            if this.own_loops() {
+                return this.chunked_read_to_string(buf);
+            }
+            let n = this.timed("read_to_string", |r| r.read_to_string(buf))?;
+            this.record_transferred(n);
+            Ok(n)
+        })
+    }
+
+    // Delegated as `read_to_end`. Our own loop checks the token between
+    // reads, so that a cancellation in the middle is not reported as
+    // `UnexpectedEof`.
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.rewinding(|this| {
+            this.check()?;
+            if this.own_loops() {
+                return this.chunked_read_exact(buf);
+            }
+            this.timed("read_exact", |r| r.read_exact(buf))?;
+            this.record_transferred(buf.len());
+            Ok(())
+        })
+    }
+}
+
+impl<T: std::io::Write> std::io::Write for Cancellable<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let _span = trace::op_span("write");
+        if let Err(e) = self.check() {
+            return self.cancelled_write_count(e);
+        }
+        if self.max_write.is_some() {
+            return self.write_min(buf, buf.len());
+        }
+        if let Some(min_size) = self.min_size {
+            return self.write_min(buf, min_size);
+        }
+        let len = self.chunk_len(buf.len());
+        match self.retry("write", |w| w.write(&buf[..len])) {
+            Ok(res) => self.inner_count(res),
+            Err(e) => self.cancelled_write_count(e),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.check()?;
+        if let Some(deferred) = &mut self.deferred_flush {
+            *deferred = true;
+            return Ok(());
+        }
+        if self.chunk_size.is_some() {
+            return self.flush_cancellable();
+        }
+        self.retry("flush", |w| w.flush())?
+    }
+    fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
+        let _span = trace::op_span("write_vectored");
+        if let Err(e) = self.check() {
+            return self.cancelled_write_count(e);
+        }
+        if self.max_write.is_some() {
+            // As the default `write_vectored`, but split by `write_min`.
+            let buf = bufs.iter().find(|b| !b.is_empty()).map_or(&[][..], |b| b);
+            return self.write_min(buf, buf.len());
+        }
+        if !self.split_vectored {
+            return match self.retry("write_vectored", |w| w.write_vectored(bufs)) {
+                Ok(res) => self.inner_count(res),
+                Err(e) => self.cancelled_write_count(e),
+            };
+        }
+        let mut total = 0;
+        for buf in bufs.iter().filter(|b| !b.is_empty()) {
+            if total > 0 && self.check_token().is_err() {
+                break;
+            }
+            let n = match self.retry("write", |w| w.write(buf)) {
+                Ok(Ok(n)) => {
+                    self.record_size(n);
+                    n
+                }
+                Ok(Err(_)) | Err(_) if total > 0 => break,
+                Ok(Err(e)) => return self.inner_count(Err(e)),
+                Err(e) => return self.cancelled_write_count(e),
+            };
+            total += n;
+            if n < buf.len() {
+                break;
+            }
+        }
+        self.inspected(Ok(total))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.rewinding(|this| {
+            this.check()?;
+            if this.own_loops() {
+                return this.chunked_write_all(buf);
+            }
+            this.timed("write_all", |w| w.write_all(buf))?;
+            this.record_transferred(buf.len());
+            Ok(())
+        })
+    }
+
+    fn write_fmt(&mut self, fmt: std::fmt::Arguments<'_>) -> std::io::Result<()> {
+        self.rewinding(|this| {
+            this.check()?;
+            if this.own_loops() {
+                return this.chunked_write_fmt(fmt);
+            }
+            this.timed("write_fmt", |w| w.write_fmt(fmt))
+        })
+    }
+}
+
+impl<T: std::io::Seek> Cancellable<T> {
+    /// Gets the length of the stream.
+    ///
+    /// It seeks to the end and then back to the current position. The token
+    /// is checked only once, before starting, so a cancellation in the middle
+    /// of the probe cannot leave the stream at the wrong position.
+    pub fn length(&mut self) -> std::io::Result<u64> {
+        self.check()?;
+        self.timed("length", Self::stream_len)
+    }
+    // The length of `s`, keeping its position.
+    fn stream_len(s: &mut T) -> std::io::Result<u64> {
+        let pos = s.stream_position()?;
+        let len = s.seek(std::io::SeekFrom::End(0));
+        if len.as_ref().map_or(true, |len| *len != pos) {
+            s.seek(std::io::SeekFrom::Start(pos))?;
+        }
+        len
+    }
+}
+
+impl<T: std::io::Seek> Cancellable<T> {
+    /// Seeks, all or nothing with respect to cancellation.
+    ///
+    /// The target position is validated first, so a seek before the start
+    /// fails without moving. For `SeekFrom::End` that needs the length of the
+    /// inner value, that is found by seeking to its end and back. Then the token is checked, the seek is done, and
+    /// the token is checked again: if it was cancelled in the meantime, the
+    /// inner value is moved back to the original position and the cancellation
+    /// error is returned. So either the seek is fully applied and `Ok` is
+    /// returned, or the position does not change.
+    pub fn seek_checked(&mut self, from: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.check()?;
+        let orig = self.timed("stream_position", |s| s.stream_position())?;
+        let base = match from {
+            std::io::SeekFrom::Start(_) => None,
+            std::io::SeekFrom::Current(offset) => Some((orig, offset)),
+            std::io::SeekFrom::End(offset) => {
+                Some((self.timed("length", Self::stream_len)?, offset))
+            }
+        };
+        if let Some((base, offset)) = base {
+            if base.checked_add_signed(offset).is_none() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "invalid seek to a negative or overflowing position",
+                ));
+            }
+        }
+        let pos = self.timed("seek", |s| s.seek(from))?;
+        if let Err(e) = self.check_token() {
+            self.timed("seek", |s| s.seek(std::io::SeekFrom::Start(orig)))?;
+            return Err(e);
+        }
+        Ok(pos)
+    }
+    /// Gets the current position of the inner value, checking the token first.
+    ///
+    /// It is a `stream_position` that fails with the cancellation error if
+    /// the token is cancelled.
+    pub fn current_position(&mut self) -> std::io::Result<u64> {
+        self.check()?;
+        self.timed("stream_position", |s| s.stream_position())
+    }
+    /// Checks that the position of the inner value is `expected`.
+    ///
+    /// This is an integrity check for resuming a transfer after a
+    /// cancellation: if the inner value was truncated or otherwise changed in
+    /// the meantime, for example a file that shrank before being reopened and
+    /// seeked to its end, its position no longer matches the offset where the
+    /// transfer stopped, such as [`Cancellable::abort_offset`], and going on
+    /// would corrupt the output silently.
+    ///
+    /// It fails with `ErrorKind::InvalidData` on a mismatch. The token is not
+    /// checked, so that it can be called right after a cancellation.
+    pub fn verify_position(&mut self, expected: u64) -> std::io::Result<()> {
+        let pos = self.timed("stream_position", |s| s.stream_position())?;
+        if pos != expected {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("stream position is {pos}, expected {expected}"),
+            ));
+        }
+        Ok(())
+    }
+    /// Rewinds the inner value if an operation is cancelled.
+    ///
+    /// The current position of the inner value is recorded, and if a `Read`
+    /// or `Write` operation fails because the token is cancelled, the inner
+    /// value is seeked back to that position, so that the whole sequence of
+    /// operations can be retried cleanly, for example on a `Cursor`. Use
+    /// [`Cancellable::set_rewind_point`] to start a new sequence.
+    ///
+    /// If seeking back fails, that error is returned instead of the
+    /// cancellation error.
+    ///
+    /// This only applies to seekable inner values, and the rewind point is
+    /// not kept by functions that replace the inner value, such as
+    /// [`Cancellable::cancellable_take`].
+    pub fn rewind_on_cancel(mut self) -> std::io::Result<Self> {
+        self.set_rewind_point()?;
+        Ok(self)
+    }
+    /// Records the current position as the rewind point.
+    ///
+    /// See [`Cancellable::rewind_on_cancel`].
+    pub fn set_rewind_point(&mut self) -> std::io::Result<()> {
+        let pos = self.inner.stream_position()?;
+        self.rewind = Some((pos, T::seek));
+        Ok(())
+    }
+}
+
+impl<T: std::io::Seek> std::io::Seek for Cancellable<T> {
+    fn seek(&mut self, from: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.check()?;
+        self.timed("seek", |s| s.seek(from))
+    }
+
+    fn rewind(&mut self) -> std::io::Result<()> {
+        self.check()?;
+        self.timed("rewind", |s| s.rewind())
+    }
+
+    fn stream_position(&mut self) -> std::io::Result<u64> {
+        self.check()?;
+        self.timed("stream_position", |s| s.stream_position())
+    }
+
+    fn seek_relative(&mut self, offset: i64) -> std::io::Result<()> {
+        self.check()?;
+        self.timed("seek_relative", |s| s.seek_relative(offset))
+    }
+}
+
+impl<T: std::io::BufRead> Cancellable<T> {
+    // Like `inner_count` but for `fill_buf`. It does not borrow `self` because
+    // the inner buffer is still borrowed.
+    fn inner_buf_error<'a>(
+        graceful: bool,
+        last_error: &mut Option<std::io::Error>,
+        err: std::io::Error,
+    ) -> std::io::Result<&'a [u8]> {
+        if graceful && err.kind() != std::io::ErrorKind::Interrupted {
+            *last_error = Some(err);
+            Ok(&[])
+        } else {
+            Err(err)
+        }
+    }
+}
+
+impl<T: std::io::BufRead> std::io::BufRead for Cancellable<T> {
+    // Provided methods are not wrapped, probably not worth it
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if let Err(e) = self.check() {
+            return if self.graceful || self.eof_on_cancel {
+                Ok(&[])
+            } else {
+                Err(e)
+            };
+        }
+        if self
+            .options
+            .as_ref()
+            .is_some_and(|o| o.retry.is_some() || o.spin.is_some() || o.delay.is_some())
+        {
+            // The buffer cannot be returned from inside the retry loop, so wait
+            // until it is filled and then ask for it again, that should be cheap.
+            match self.retry("fill_buf", |r| r.fill_buf().map(drop)) {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => return Self::inner_buf_error(self.graceful, &mut self.last_error, e),
+                Err(e) => {
+                    return if self.graceful || self.eof_on_cancel {
+                        Ok(&[])
+                    } else {
+                        Err(e)
+                    }
+                }
+            }
+        }
+        match Self::time(self.options.as_deref(), "fill_buf", &mut self.inner, |r| {
+            r.fill_buf()
+        }) {
+            Err(e) => {
+                let enabled = self.options.as_ref().is_some_and(|o| o.cancel_on_error);
+                cancel_on_inner_error(enabled, &self.token, &e);
+                Self::inner_buf_error(self.graceful, &mut self.last_error, e)
+            }
+            res => res,
+        }
+    }
+    fn consume(&mut self, amt: usize) {
+        if self.fuse && self.check_token().is_err() {
+            return;
+        }
+        Self::time(self.options.as_deref(), "consume", &mut self.inner, |r| {
+            r.consume(amt)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{self, Read, Seek, Write};
+    use std::time::Duration;
+
+    fn inf_write(ct: CancellationToken) -> io::Result<()> {
+        let w = io::empty();
+        let mut w = Cancellable::new(w, ct);
+        for _i in 0..10 {
+            w.write_all(&[0])?;
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+
+    fn inf_read(ct: CancellationToken) -> io::Result<()> {
+        let r = io::empty();
+        let mut r = Cancellable::new(r, ct);
+        let mut data = [0];
+        for _i in 0..10 {
+            let _ = r.read(&mut data)?;
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+
+    fn inf_seek(ct: CancellationToken) -> io::Result<()> {
+        let r = io::empty();
+        let mut r = Cancellable::new(r, ct);
+        for _i in 0..10 {
+            r.seek(io::SeekFrom::Start(0))?;
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+
+    // Writes everything into a `Vec`, cancelling the token after the first call.
+    struct CancelAfterWrite {
+        data: Vec<u8>,
+        calls: usize,
+        ct: CancellationToken,
+    }
+
+    impl Write for CancelAfterWrite {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.data.extend_from_slice(buf);
+            self.calls += 1;
+            self.ct.cancel();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Rejects writes bigger than one byte, cancelling the token after `cancel_at` writes.
+    struct OneByteSink {
+        data: Vec<u8>,
+        cancel_at: usize,
+        ct: CancellationToken,
+    }
+
+    impl Write for OneByteSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.len() > 1 {
+                return Err(io::ErrorKind::InvalidInput.into());
+            }
+            self.data.extend_from_slice(buf);
+            if self.data.len() == self.cancel_at {
+                self.ct.cancel();
+            }
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_with_max_write() {
+        let ct = CancellationToken::new();
+        let sink = OneByteSink {
+            data: Vec::new(),
+            cancel_at: 100,
+            ct: ct.clone(),
+        };
+        let mut w = Cancellable::with_max_write(sink, ct.clone(), 1);
+        assert_eq!(w.write(b"abcd").unwrap(), 4);
+        w.write_all(b"efgh").unwrap();
+        write!(w, "{}", 12).unwrap();
+        assert_eq!(w.write_vectored(&[io::IoSlice::new(b"xy")]).unwrap(), 2);
+        assert_eq!(w.get_ref().data, b"abcdefgh12xy");
+
+        // Cancelled partway, the partial count is returned.
+        let ct = CancellationToken::new();
+        let sink = OneByteSink {
+            data: Vec::new(),
+            cancel_at: 3,
+            ct: ct.clone(),
+        };
+        let mut w = Cancellable::with_max_write(sink, ct, 1);
+        assert_eq!(w.write(b"abcdef").unwrap(), 3);
+        assert_eq!(
+            w.write(b"def").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+        assert_eq!(w.get_ref().data, b"abc");
+    }
+
+    // Reads one byte at a time, cancelling the token after `cancel_at` bytes.
+    struct CancelAfterRead {
+        data: &'static [u8],
+        cancel_at: usize,
+        ct: CancellationToken,
+    }
+
+    impl Read for CancelAfterRead {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.data.read(&mut buf[..1])?;
+            self.cancel_at = self.cancel_at.saturating_sub(n);
+            if self.cancel_at == 0 {
+                self.ct.cancel();
+            }
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_op_limit() {
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::with_op_limit(&b"abcdefgh"[..], ct.clone(), 3).chunked(1);
+        let mut buf = [0; 2];
+        assert_eq!(r.read(&mut buf).unwrap(), 1);
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"bc");
+        assert_eq!(r.read(&mut buf).unwrap(), 1);
+        let err = r.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(
+            classify(&err),
+            Outcome::Cancelled(Some("operation limit reached".to_string()))
+        );
+        assert!(ct.check().is_ok());
+
+        let mut w = Cancellable::with_op_limit(io::Cursor::new(Vec::new()), ct, 2);
+        w.write_all(b"ab").unwrap();
+        w.rewind().unwrap();
+        assert!(w.write_all(b"cd").is_err());
+        assert_eq!(w.get_ref().get_ref(), b"ab");
+    }
+
+    // Reads a few bytes at a time.
+    struct Trickle(&'static [u8]);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(3);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_min_size() {
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(Trickle(b"abcdefghijklmnopq"), ct.clone()).min_size(8);
+        let mut buf = [0; 16];
+        assert_eq!(r.read(&mut buf).unwrap(), 9);
+        assert_eq!(&buf[..9], b"abcdefghi");
+        assert_eq!(r.read(&mut buf[..4]).unwrap(), 4);
+        // The end of the stream returns the partial count.
+        assert_eq!(r.read(&mut buf).unwrap(), 4);
+        assert_eq!(r.read(&mut buf).unwrap(), 0);
+
+        let ct = CancellationToken::new();
+        let inner = CancelAfterRead {
+            data: b"abcdef",
+            cancel_at: 3,
+            ct: ct.clone(),
+        };
+        let mut r = Cancellable::new(inner, ct).min_size(5);
+        assert_eq!(r.read(&mut buf).unwrap(), 3);
+        let err = r.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        let ct = CancellationToken::new();
+        let inner = CancelAfterWrite {
+            data: Vec::new(),
+            calls: 0,
+            ct: ct.clone(),
+        };
+        let mut w = Cancellable::new(inner, ct).chunked(2).min_size(4);
+        assert_eq!(w.write(b"abcdef").unwrap(), 2);
+        assert!(w.write(b"cdef").is_err());
+    }
+
+    #[test]
+    fn test_with_inner_mut() {
+        let ct = CancellationToken::new();
+        let mut w = Cancellable::new(Vec::new(), ct.clone());
+        let len = w
+            .with_inner_mut(|v| {
+                v.extend_from_slice(b"abc");
+                v.len()
+            })
+            .unwrap();
+        assert_eq!(len, 3);
+        ct.cancel();
+        let err = w.with_inner_mut(|v| v.clear()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(w.get_ref(), b"abc");
+    }
+
+    #[test]
+    fn test_std_constructors() {
+        let ct = CancellationToken::new();
+        let mut empty = Cancellable::empty(ct.clone());
+        let mut sink = Cancellable::sink(ct.clone());
+        let mut repeat = Cancellable::repeat(7, ct.clone());
+        let mut buf = [0; 3];
+        assert_eq!(empty.read(&mut buf).unwrap(), 0);
+        assert_eq!(sink.write(b"abc").unwrap(), 3);
+        assert_eq!(repeat.read(&mut buf).unwrap(), 3);
+        assert_eq!(buf, [7; 3]);
+        ct.cancel();
+        assert!(empty.read(&mut buf).is_err());
+        assert!(sink.write(b"abc").is_err());
+        assert!(repeat.read(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_until_cancelled() {
+        let ct = CancellationToken::new();
+        let inner = CancelAfterRead {
+            data: b"abcdef",
+            cancel_at: 3,
+            ct: ct.clone(),
+        };
+        let mut r = Cancellable::new(inner, ct);
+        let mut buf = [0; 5];
+        let (n, err) = r.read_until_cancelled(&mut buf);
+        assert_eq!(n, 3);
+        assert_eq!(err.unwrap().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(&buf[..3], b"abc");
+
+        let mut r = Cancellable::new(&b"abc"[..], CancellationToken::new());
+        let (n, err) = r.read_until_cancelled(&mut buf);
+        assert_eq!(n, 3);
+        assert!(err.is_none());
+
+        let ct = CancellationToken::new();
+        let inner = CancelAfterWrite {
+            data: Vec::new(),
+            calls: 0,
+            ct: ct.clone(),
+        };
+        let mut w = Cancellable::new(inner, ct).chunked(2);
+        let (n, err) = w.write_until_cancelled(b"abcdef");
+        assert_eq!(n, 2);
+        assert_eq!(err.unwrap().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(w.get_ref().data, b"ab");
+    }
+
+    #[test]
+    fn test_read_exact_cancelled() {
+        let ct = CancellationToken::new();
+        let inner = CancelAfterRead {
+            data: b"abcdef",
+            cancel_at: 3,
+            ct: ct.clone(),
+        };
+        let mut r = Cancellable::new(inner, ct).non_retryable();
+        let mut buf = [0; 5];
+        let err = r.read_exact(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(&buf[..3], b"abc");
+
+        // By default it is delegated, and the cancellation is seen later.
+        let ct = CancellationToken::new();
+        let inner = CancelAfterRead {
+            data: b"abcdef",
+            cancel_at: 3,
+            ct: ct.clone(),
+        };
+        let mut r = Cancellable::new(inner, ct);
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"abcde");
+        assert!(r.read_exact(&mut buf).is_err());
+
+        // A genuine short read is still an `UnexpectedEof`.
+        let ct = CancellationToken::new();
+        let inner = CancelAfterRead {
+            data: b"abc",
+            cancel_at: usize::MAX,
+            ct: ct.clone(),
+        };
+        let mut r = Cancellable::new(inner, ct);
+        let err = r.read_exact(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let mut r = Cancellable::new(&b"abcdef"[..], CancellationToken::new());
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"abcde");
+    }
+
+    #[test]
+    fn test_write_vectored_split() {
+        let ct = CancellationToken::new();
+        let inner = CancelAfterWrite {
+            data: Vec::new(),
+            calls: 0,
+            ct: ct.clone(),
+        };
+        let mut w = Cancellable::new(inner, ct).split_vectored();
+        let bufs = [
+            io::IoSlice::new(b"abc"),
+            io::IoSlice::new(b"de"),
+            io::IoSlice::new(b"f"),
+        ];
+        let n = w.write_vectored(&bufs).unwrap();
+        assert_eq!(n, 3);
+        assert_eq!(w.get_ref().data, b"abc");
+        assert_eq!(w.get_ref().calls, 1);
+        assert_eq!(w.abort_offset(), Some(3));
+
+        let err = w.write_vectored(&bufs[1..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(w.get_ref().calls, 1);
+    }
+
+    // A reader that must not be used once the token is cancelled.
+    struct PanicAfterCancel(CancellationToken);
+
+    impl Read for PanicAfterCancel {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            assert!(self.0.check().is_ok(), "read after cancel");
+            buf[0] = 42;
+            Ok(1)
+        }
+    }
+
+    impl io::BufRead for PanicAfterCancel {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            assert!(self.0.check().is_ok(), "fill_buf after cancel");
+            Ok(b"x")
+        }
+        fn consume(&mut self, _amt: usize) {
+            assert!(self.0.check().is_ok(), "consume after cancel");
+        }
+    }
+
+    #[test]
+    fn test_fuse() {
+        use io::BufRead;
+
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(PanicAfterCancel(ct.clone()), ct.clone()).fuse();
+        let mut data = [0];
+        assert_eq!(r.read(&mut data).unwrap(), 1);
+        assert_eq!(r.fill_buf().unwrap(), b"x");
+        r.consume(1);
+
+        ct.cancel();
+        r.consume(1);
+        let err = r.read(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        let err = r.fill_buf().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        r.consume(1);
+        let err = r.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    // A reader that fails after returning some data.
+    struct FailingReader(usize);
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0 == 0 {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            self.0 -= 1;
+            buf[0] = 1;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_graceful() {
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(io::repeat(1), ct.clone()).graceful();
+        let mut data = [0; 4];
+        assert_eq!(r.read(&mut data).unwrap(), 4);
+        ct.cancel();
+        assert_eq!(r.read(&mut data).unwrap(), 0);
+        assert!(r.take_error().is_none());
+    }
+
+    #[test]
+    fn test_read_eof_write_error() {
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(&b"abc"[..], ct.clone()).read_eof_write_error();
+        let mut w = Cancellable::new(Vec::new(), ct.clone()).read_eof_write_error();
+        let mut data = [0; 2];
+        assert_eq!(r.read(&mut data).unwrap(), 2);
+        assert_eq!(w.write(&data).unwrap(), 2);
+        ct.cancel();
+        assert_eq!(r.read(&mut data).unwrap(), 0);
+        assert_eq!(io::BufRead::fill_buf(&mut r).unwrap(), b"");
+        let err = w.write(&data).unwrap_err();
+        assert_eq!(classify(&err), Outcome::Cancelled(None));
+        let err = w.write_all(&data).unwrap_err();
+        assert_eq!(classify(&err), Outcome::Cancelled(None));
+        assert_eq!(w.into_inner(), b"ab");
+    }
+
+    #[test]
+    fn test_take_error() {
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(FailingReader(3), ct).graceful();
+        let mut data = [0; 1];
+        let mut total = 0;
+        loop {
+            match r.read(&mut data).unwrap() {
+                0 => break,
+                n => total += n,
+            }
+        }
+        assert_eq!(total, 3);
+        let err = r.take_error().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert!(r.take_error().is_none());
+    }
+
+    // A reader that fails with `kind` the first `fails` times it is called.
+    struct TransientReader {
+        fails: usize,
+        kind: io::ErrorKind,
+        calls: usize,
+        ct: Option<CancellationToken>,
+    }
+
+    impl Read for TransientReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.calls += 1;
+            if self.calls == 2 {
+                if let Some(ct) = &self.ct {
+                    ct.cancel();
+                }
+            }
+            if self.calls <= self.fails {
+                return Err(self.kind.into());
+            }
+            buf[0] = 7;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_retry() {
+        let inner = TransientReader {
+            fails: 2,
+            kind: io::ErrorKind::Interrupted,
+            calls: 0,
+            ct: None,
+        };
+        let policy = RetryPolicy {
+            max_retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+        let mut r = Cancellable::with_retry(inner, CancellationToken::new(), policy);
+        let mut data = [0];
+        assert_eq!(r.read(&mut data).unwrap(), 1);
+        assert_eq!(data, [7]);
+        assert_eq!(r.get_ref().calls, 3);
+
+        let inner = TransientReader {
+            fails: 2,
+            kind: io::ErrorKind::WouldBlock,
+            calls: 0,
+            ct: None,
+        };
+        let policy = RetryPolicy {
+            max_retries: 1,
+            backoff: Duration::from_millis(1),
+        };
+        let mut r = Cancellable::with_retry(inner, CancellationToken::new(), policy);
+        let err = r.read(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(r.get_ref().calls, 2);
+    }
+
+    #[test]
+    fn test_retry_cancel() {
+        let ct = CancellationToken::new();
+        let inner = TransientReader {
+            fails: usize::MAX,
+            kind: io::ErrorKind::WouldBlock,
+            calls: 0,
+            ct: Some(ct.clone()),
+        };
+        let policy = RetryPolicy {
+            max_retries: 100,
+            backoff: Duration::from_millis(1),
+        };
+        let mut r = Cancellable::with_retry(inner, ct, policy);
+        let err = r.read(&mut [0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(r.get_ref().calls, 2);
+    }
+
+    // A reader that takes its time.
+    struct SlowReader(Duration);
+
+    impl Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            std::thread::sleep(self.0);
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn test_warn_threshold() {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let mut r = Cancellable::with_warn_threshold(
+            SlowReader(Duration::from_millis(50)),
+            CancellationToken::new(),
+            Duration::from_millis(10),
+            {
+                let warnings = warnings.clone();
+                move |op, elapsed| warnings.lock().unwrap().push((op, elapsed))
+            },
+        );
+        let mut data = [0; 4];
+        assert_eq!(r.read(&mut data).unwrap(), 4);
+        r.read_exact(&mut data).unwrap();
+        let warnings = warnings.lock().unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].0, "read");
+        // `read_exact` is delegated to the inner value.
+        assert_eq!(warnings[1].0, "read_exact");
+        assert!(warnings[0].1 >= Duration::from_millis(50));
+
+        let mut r = Cancellable::with_warn_threshold(
+            SlowReader(Duration::ZERO),
+            CancellationToken::new(),
+            Duration::from_secs(10),
+            |_, _| panic!("unexpected warning"),
+        );
+        assert_eq!(r.read(&mut data).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_chunked() {
+        let ct = CancellationToken::new();
+        let inner = CancelAfterWrite {
+            data: Vec::new(),
+            calls: 0,
+            ct: ct.clone(),
+        };
+        let mut w = Cancellable::new(inner, ct).chunked(2);
+        let err = w.write_all(b"abcdef").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(w.get_ref().data, b"ab");
+
+        let mut r = Cancellable::new(&b"0123456789"[..], CancellationToken::new()).chunked(3);
+        let mut data = [0; 5];
+        assert_eq!(r.read(&mut data).unwrap(), 3);
+        r.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"34567");
+        let mut s = String::new();
+        assert_eq!(r.read_to_string(&mut s).unwrap(), 2);
+        assert_eq!(s, "89");
+        let err = r.read_exact(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut w = Cancellable::new(Vec::new(), CancellationToken::new()).chunked(1);
+        write!(w, "{}-{}", 12, 34).unwrap();
+        assert_eq!(w.get_ref(), b"12-34");
+    }
+
+    // A writer whose flush never makes progress.
+    struct StuckFlush;
+
+    impl Write for StuckFlush {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    // A reader that is always interrupted, and cancels the token.
+    struct InterruptedReader(CancellationToken);
+
+    impl Read for InterruptedReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            self.0.cancel();
+            Err(io::ErrorKind::Interrupted.into())
+        }
+    }
+
+    #[test]
+    fn test_rewind_on_cancel() {
+        let ct = CancellationToken::new();
+        let inner = io::Cursor::new(b"abcdefgh".to_vec());
+        let mut r = Cancellable::new(inner, ct.clone())
+            .rewind_on_cancel()
+            .unwrap();
+        let mut buf = [0; 2];
+        r.read_exact(&mut buf).unwrap();
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(r.get_ref().position(), 4);
+        ct.cancel();
+        assert!(r.read_exact(&mut buf).is_err());
+        assert_eq!(r.get_ref().position(), 0);
+
+        // Retry after a new rewind point.
+        assert!(ct.reset_if(true));
+        r.read_exact(&mut buf).unwrap();
+        r.set_rewind_point().unwrap();
+        assert_eq!(r.read(&mut buf).unwrap(), 2);
+        ct.cancel();
+        assert!(r.read(&mut buf).is_err());
+        assert_eq!(r.get_ref().position(), 2);
+
+        let ct = CancellationToken::new();
+        let mut w = Cancellable::new(io::Cursor::new(Vec::new()), ct.clone())
+            .chunked(2)
+            .rewind_on_cancel()
+            .unwrap();
+        w.write_all(b"1234").unwrap();
+        ct.cancel();
+        assert!(w.write_all(b"5678").is_err());
+        assert_eq!(w.get_ref().position(), 0);
+    }
+
+    #[test]
+    fn test_inspect() {
+        let ct = CancellationToken::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut r = Cancellable::inspect(&b"abc"[..], ct.clone(), {
+            let seen = seen.clone();
+            move |res| {
+                seen.lock()
+                    .unwrap()
+                    .push(res.as_ref().map(|n| *n).map_err(|e| e.kind()))
+            }
+        });
+        let mut buf = [0; 2];
+        assert_eq!(r.read(&mut buf).unwrap(), 2);
+        assert_eq!(r.read(&mut buf).unwrap(), 1);
+        assert_eq!(r.read(&mut buf).unwrap(), 0);
+        ct.cancel();
+        assert!(r.read(&mut buf).is_err());
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [Ok(2), Ok(1), Ok(0), Err(io::ErrorKind::BrokenPipe)]
+        );
+    }
+
+    #[test]
+    fn test_with_on_abort() {
+        let ct = CancellationToken::new();
+        let aborts = Arc::new(AtomicU64::new(0));
+        let mut r = Cancellable::with_on_abort(&b"abcdef"[..], ct.clone(), {
+            let aborts = aborts.clone();
+            move || {
+                aborts.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        let mut buf = [0; 2];
+        assert_eq!(r.read(&mut buf).unwrap(), 2);
+        assert_eq!(r.read(&mut buf).unwrap(), 2);
+        assert_eq!(aborts.load(Ordering::Relaxed), 0);
+        ct.cancel();
+        assert_eq!(aborts.load(Ordering::Relaxed), 0);
+        assert!(r.read(&mut buf).is_err());
+        assert!(r.read_exact(&mut buf).is_err());
+        let mut buf = Vec::new();
+        assert!(r.read_to_end(&mut buf).is_err());
+        assert_eq!(aborts.load(Ordering::Relaxed), 3);
+    }
+
+    struct WouldBlockReader;
+
+    impl Read for WouldBlockReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    #[test]
+    fn test_spin_nonblocking() {
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(WouldBlockReader, ct.clone())
+            .spin_nonblocking(Duration::from_millis(1));
+        let t = std::thread::spawn({
+            let ct = ct.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(50));
+                ct.cancel();
+            }
+        });
+        let mut buf = [0; 4];
+        let err = r.read(&mut buf).unwrap_err();
+        assert_eq!(classify(&err), Outcome::Cancelled(None));
+        t.join().unwrap();
+
+        // Without it, the error is returned.
+        let mut r = Cancellable::new(WouldBlockReader, CancellationToken::new());
+        assert_eq!(
+            r.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn test_abort_offset() {
+        let ct = CancellationToken::new();
+        let inner = CancelAfterRead {
+            data: b"0123456789",
+            cancel_at: 6,
+            ct: ct.clone(),
+        };
+        let mut r = Cancellable::new(inner, ct).non_retryable();
+        assert_eq!(r.abort_offset(), None);
+        let mut buf = [0; 4];
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(r.abort_offset(), None);
+        let err = r.read_exact(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(r.abort_offset(), Some(6));
+
+        // A seekable one matches its position.
+        let ct = CancellationToken::new();
+        let mut w = Cancellable::new(io::Cursor::new(Vec::new()), ct.clone()).chunked(3);
+        w.write_all(b"abcdef").unwrap();
+        w.write_all(b"gh").unwrap();
+        let mut r = Cancellable::new(&b"0123456789"[..], ct.clone());
+        r.read_exact(&mut buf).unwrap();
+        ct.cancel();
+        assert!(w.write_all(b"ijk").is_err());
+        assert!(r.read(&mut buf).is_err());
+        assert_eq!(w.abort_offset(), Some(8));
+        assert_eq!(
+            w.abort_offset(),
+            Some(w.get_mut().stream_position().unwrap())
+        );
+        assert_eq!(r.abort_offset(), Some(4));
+    }
+
+    #[test]
+    fn test_last_op_aborted() {
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(&b"abc"[..], ct.clone()).graceful();
+        let mut buf = [0; 2];
+        assert!(!r.last_op_aborted());
+        assert_eq!(r.read(&mut buf).unwrap(), 2);
+        assert!(!r.last_op_aborted());
+        assert_eq!(r.read(&mut buf).unwrap(), 1);
+        assert_eq!(r.read(&mut buf).unwrap(), 0);
+        assert!(!r.last_op_aborted());
+        ct.cancel();
+        assert_eq!(r.read(&mut buf).unwrap(), 0);
+        assert!(r.last_op_aborted());
+
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::with_op_limit(&b"abc"[..], ct, 1);
+        assert_eq!(r.read(&mut buf).unwrap(), 2);
+        assert!(!r.last_op_aborted());
+        assert!(r.read(&mut buf).is_err());
+        assert!(r.last_op_aborted());
+    }
+
+    #[test]
+    fn test_non_retryable() {
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(InterruptedReader(ct.clone()), ct).non_retryable();
+        let mut buf = [0; 4];
+        // A naive retry loop terminates.
+        let err = loop {
+            match r.read(&mut buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => break e,
+                Ok(_) => unreachable!(),
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(InterruptedReader(ct.clone()), ct).non_retryable();
+        let err = r.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(InterruptedReader(ct.clone()), ct).non_retryable();
+        let err = r.read_exact(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        // Normal reads are not affected.
+        let mut r = Cancellable::new(&b"abcde"[..], CancellationToken::new()).non_retryable();
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+        let mut rest = Vec::new();
+        assert_eq!(r.read_to_end(&mut rest).unwrap(), 1);
+        assert_eq!(rest, b"e");
+    }
+
+    #[test]
+    fn test_flush_cancellable() {
+        let ct = CancellationToken::new();
+        let mut w = Cancellable::new(StuckFlush, ct.clone());
+        std::thread::spawn({
+            let ct = ct.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(50));
+                ct.cancel();
+            }
+        });
+        let err = w.flush_cancellable().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        let mut w = Cancellable::new(StuckFlush, CancellationToken::new());
+        let err = w.flush().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let mut w = Cancellable::with_call_timeout(
+            StuckFlush,
+            CancellationToken::new(),
+            Duration::from_millis(20),
+        );
+        let err = w.flush().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_call_timeout() {
+        let mut r = Cancellable::with_call_timeout(
+            SlowReader(Duration::from_millis(50)),
+            CancellationToken::new(),
+            Duration::from_millis(120),
+        )
+        .chunked(4);
+        let mut data = [0; 100];
+        let err = r.read_exact(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Each call has its own budget.
+        let mut data = [0; 8];
+        r.read_exact(&mut data).unwrap();
+        r.read_exact(&mut data).unwrap();
+    }
+
+    #[test]
+    fn test_with_deadline() {
+        let ct = CancellationToken::new();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let mut r = Cancellable::with_deadline(io::repeat(0), ct.clone(), deadline);
+        let mut data = [0; 4];
+        assert_eq!(r.read(&mut data).unwrap(), 4);
+        std::thread::sleep(Duration::from_millis(60));
+        let err = r.read(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(ct.check().is_ok());
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut r = Cancellable::with_deadline(io::repeat(0), ct.clone(), deadline);
+        assert_eq!(r.read(&mut data).unwrap(), 4);
+        ct.cancel();
+        let err = r.read(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_chained_options() {
+        let ct = CancellationToken::new();
+        let results = Arc::new(AtomicU64::new(0));
+        let mut r = Cancellable::new(io::repeat(0), ct)
+            .retry_policy(RetryPolicy::default())
+            .deadline(Instant::now() + Duration::from_secs(60))
+            .op_limit(2)
+            .on_result({
+                let results = Arc::clone(&results);
+                move |_| {
+                    results.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        let mut data = [0; 4];
+        assert_eq!(r.read(&mut data).unwrap(), 4);
+        assert_eq!(r.read(&mut data).unwrap(), 4);
+        let err = r.read(&mut data).unwrap_err();
+        assert_eq!(
+            classify(&err),
+            Outcome::Cancelled(Some("operation limit reached".to_string()))
+        );
+        assert_eq!(results.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_cancel_token_on_error() {
+        let ct = CancellationToken::new();
+        let mut failing = Cancellable::cancel_token_on_error(FailingReader(1), ct.clone());
+        let mut sibling = Cancellable::new(io::repeat(0), ct.clone());
+        let mut data = [0; 1];
+        assert_eq!(failing.read(&mut data).unwrap(), 1);
+        assert_eq!(sibling.read(&mut data).unwrap(), 1);
+
+        let err = failing.read(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(
+            ct.cancel_cause(),
+            Some(CancelCause::InnerError(io::ErrorKind::ConnectionReset))
+        );
+        let err = sibling.read(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(
+            classify(&err),
+            Outcome::Cancelled(Some(
+                io::Error::from(io::ErrorKind::ConnectionReset).to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_checkpoint_every() {
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(io::repeat(0), ct.clone())
+            .checkpoint_every(Duration::from_millis(200));
+        let mut data = [0; 4];
+        assert_eq!(r.read(&mut data).unwrap(), 4);
+        ct.cancel();
+        assert_eq!(r.read(&mut data).unwrap(), 4);
+        std::thread::sleep(Duration::from_millis(250));
+        let err = r.read(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        let err = r.read(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    // Cancels `ct` after `delay`, and returns how long `op` took.
+    fn latency(ct: &CancellationToken, delay: Duration, op: impl FnOnce()) -> Duration {
+        let start = Instant::now();
+        let th = std::thread::spawn({
+            let ct = ct.clone();
+            move || {
+                std::thread::sleep(delay);
+                ct.cancel();
+            }
+        });
+        op();
+        th.join().unwrap();
+        start.elapsed()
+    }
+
+    #[test]
+    fn test_injected_delay() {
+        let ct = CancellationToken::new();
+        let mut r =
+            Cancellable::with_injected_delay(&b"abcd"[..], ct.clone(), Duration::from_millis(5));
+        let mut buf = [0; 4];
+        let start = Instant::now();
+        assert_eq!(r.read(&mut buf[..2]).unwrap(), 2);
+        assert!(start.elapsed() >= Duration::from_millis(5));
+
+        // Cancelled in the middle of the sleep.
+        let ct = CancellationToken::new();
+        let mut r =
+            Cancellable::with_injected_delay(io::repeat(0), ct.clone(), Duration::from_secs(60));
+        let took = latency(&ct, Duration::from_millis(20), || {
+            assert!(r.read(&mut buf).is_err());
+        });
+        assert!(took < Duration::from_secs(10));
+
+        // A big read_exact is stopped between chunks.
+        let ct = CancellationToken::new();
+        let mut r =
+            Cancellable::with_injected_delay(io::repeat(0), ct.clone(), Duration::from_millis(2))
+                .chunked(1);
+        let mut big = vec![0; 100_000];
+        let took = latency(&ct, Duration::from_millis(20), || {
+            assert!(r.read_exact(&mut big).is_err());
+        });
+        assert!(took < Duration::from_secs(10));
+
+        // With checkpoint_every, the cancellation is seen at the next checkpoint.
+        let ct = CancellationToken::new();
+        let mut r =
+            Cancellable::with_injected_delay(io::repeat(0), ct.clone(), Duration::from_millis(2))
+                .chunked(1)
+                .checkpoint_every(Duration::from_millis(100));
+        let took = latency(&ct, Duration::from_millis(20), || {
+            assert!(r.read_exact(&mut big).is_err());
+        });
+        assert!(took >= Duration::from_millis(100));
+        assert!(took < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_write() {
+        let ct = CancellationToken::new();
+        let th = std::thread::spawn({
+            let ct = ct.clone();
+            move || {
+                inf_write(ct).unwrap();
+            }
+        });
+        ct.cancel();
+        let err = th.join().unwrap_err();
+        let err = err.downcast::<String>().unwrap();
+        assert!(err.contains("BrokenPipe"));
+    }
+
+    #[test]
+    fn test_depends_on() {
+        //     top
+        //    /   \
+        // left   right
+        //    \   /
+        //    bottom
+        let top = CancellationToken::new();
+        let left = CancellationToken::new();
+        let right = CancellationToken::new();
+        let bottom = CancellationToken::new();
+        left.depends_on(&top);
+        right.depends_on(&top);
+        bottom.depends_on(&left);
+        bottom.depends_on(&right);
+
+        left.cancel();
+        assert!(left.check().is_err());
+        assert!(bottom.check().is_err());
+        assert!(top.check().is_ok());
+        assert!(right.check().is_ok());
+
+        top.cancel();
+        assert!(right.check().is_err());
+    }
+
+    #[test]
+    fn test_cancelled_sources() {
+        let sources: Vec<_> = (0..3).map(|_| CancellationToken::new()).collect();
+        let merged = CancellationToken::any(&sources);
+        assert!(merged.check().is_ok());
+        assert!(merged.cancelled_sources().is_empty());
+
+        sources[2].cancel();
+        sources[0].cancel();
+        assert!(merged.check().is_err());
+        let mut cancelled = merged.cancelled_sources();
+        cancelled.sort_by_key(|t| sources.iter().position(|s| s == t));
+        assert_eq!(cancelled, [sources[0].clone(), sources[2].clone()]);
+    }
+
+    #[test]
+    fn test_merge_all() {
+        let a = CancellationToken::new();
+        let b = CancellationToken::new();
+        let (merged, guard) = CancellationToken::merge_all(&[a.clone(), b.clone()]);
+        drop(guard);
+        a.cancel();
+        assert!(merged.check().is_ok());
+        assert!(merged.cancelled_sources().is_empty());
+
+        let (merged, _guard) = CancellationToken::merge_all(&[a.clone(), b.clone()]);
+        assert!(merged.check().is_err());
+        let (merged, _guard) = CancellationToken::merge_all(std::slice::from_ref(&b));
+        b.cancel();
+        assert!(merged.check().is_err());
+    }
+
+    #[test]
+    fn test_first_of() {
+        let primary = CancellationToken::new();
+        let fallback = CancellationToken::new();
+        let ct = CancellationToken::first_of([Some(primary.clone()), Some(fallback.clone())]);
+        assert_eq!(ct, primary);
+        let ct = CancellationToken::first_of([None, Some(fallback.clone())]);
+        assert_eq!(ct, fallback);
+        let ct = CancellationToken::first_of([None, None]);
+        assert_ne!(ct, primary);
+        assert_ne!(ct, fallback);
+        assert!(ct.check().is_ok());
+    }
+
+    #[test]
+    fn test_depends_on_cancelled() {
+        let upstream = CancellationToken::new();
+        upstream.cancel();
+        let ct = CancellationToken::new();
+        ct.depends_on(&upstream);
+        assert!(ct.check().is_err());
+    }
+
+    #[test]
+    fn test_boxed_write() {
+        let ct = CancellationToken::new();
+        let w = Cancellable::new(Vec::new(), ct.clone()).fuse();
+        let mut w = w.boxed_write();
+        w.write_all(b"abc").unwrap();
+        assert_eq!(w.token(), &ct);
+
+        ct.cancel();
+        let err = w.write_all(b"def").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        let mut sinks: Vec<Cancellable<Box<dyn Write + Send>>> = vec![
+            Cancellable::new(Vec::new(), ct.clone()).boxed_write(),
+            Cancellable::new(Box::new(io::sink()), ct.clone()),
+        ];
+        for w in &mut sinks {
+            let err = w.write(b"x").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        }
+    }
+
+    #[test]
+    fn test_cancel_after_bytes() {
+        let ct = CancellationToken::cancel_after_bytes(100);
+        ct.add_progress(60);
+        assert!(ct.check().is_ok());
+        ct.add_progress(30);
+        assert!(ct.check().is_ok());
+        ct.add_progress(30);
+        assert_eq!(ct.progress(), 120);
+        assert!(ct.check().is_err());
+
+        let ct = CancellationToken::new();
+        ct.add_progress(u64::MAX);
+        assert!(ct.check().is_ok());
+    }
+
+    // A waker that counts how many times it is woken.
+    #[derive(Default)]
+    struct CountWaker(std::sync::atomic::AtomicUsize);
+
+    impl std::task::Wake for CountWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_poll_cancelled() {
+        use std::future::Future;
+        use std::task::{Context, Poll};
+
+        let ct = CancellationToken::new();
+        let count = Arc::new(CountWaker::default());
+        let waker = std::task::Waker::from(count.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        assert_eq!(ct.poll_cancelled(&mut cx), Poll::Pending);
+        let mut fut = std::pin::pin!(ct.cancelled());
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(count.0.load(Ordering::Relaxed), 0);
+
+        std::thread::spawn({
+            let ct = ct.clone();
+            move || ct.cancel()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(count.0.load(Ordering::Relaxed), 1);
+        assert_eq!(ct.poll_cancelled(&mut cx), Poll::Ready(()));
+        assert_eq!(fut.poll(&mut cx), Poll::Ready(()));
+    }
+
+    #[test]
+    fn test_cancelled_drop() {
+        use std::future::Future;
+        use std::task::{Context, Poll};
+
+        let ct = CancellationToken::new();
+        let mut kept = std::pin::pin!(ct.cancelled());
+        for _ in 0..100 {
+            let count = Arc::new(CountWaker::default());
+            let waker = std::task::Waker::from(count);
+            let mut cx = Context::from_waker(&waker);
+            let mut fut = std::pin::pin!(ct.cancelled());
+            assert_eq!(fut.as_mut().poll(&mut cx), Poll::Pending);
+            // Polled with another waker, it replaces the previous one.
+            assert_eq!(kept.as_mut().poll(&mut cx), Poll::Pending);
+        }
+        assert_eq!(ct.inner.wakers.lock().unwrap().list.len(), 1);
+
+        let count = Arc::new(CountWaker::default());
+        let waker = std::task::Waker::from(count.clone());
+        assert_eq!(
+            kept.as_mut().poll(&mut Context::from_waker(&waker)),
+            Poll::Pending
+        );
+        ct.cancel();
+        assert_eq!(count.0.load(Ordering::Relaxed), 1);
+        assert_eq!(kept.poll(&mut Context::from_waker(&waker)), Poll::Ready(()));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_future_boxed() {
+        let tokens: Vec<_> = (0..4).map(|_| CancellationToken::new()).collect();
+        let mut futs: Vec<_> = tokens.iter().map(|t| t.cancelled_future_boxed()).collect();
+        let task = tokio::spawn({
+            let ct = tokens[2].clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                ct.cancel();
+            }
+        });
+        let first = std::future::poll_fn(|cx| {
+            match futs.iter_mut().position(|f| f.as_mut().poll(cx).is_ready()) {
+                Some(i) => std::task::Poll::Ready(i),
+                None => std::task::Poll::Pending,
+            }
+        })
+        .await;
+        assert_eq!(first, 2);
+        task.await.unwrap();
+    }
+
+    #[test]
+    fn test_scope_spawn() {
+        let ct = CancellationToken::new();
+        let bytes = Arc::new(AtomicU64::new(0));
+        let handle = ct.scope_spawn({
+            let bytes = bytes.clone();
+            move |token| {
+                let mut r = Cancellable::new(io::repeat(0), token);
+                let mut buf = [0; 4];
+                while let Ok(n) = r.read(&mut buf) {
+                    bytes.fetch_add(n as u64, Ordering::Relaxed);
+                }
+            }
+        });
+        while bytes.load(Ordering::Relaxed) == 0 {
+            std::thread::yield_now();
+        }
+        let child = handle.token().clone();
+        drop(handle);
+        // The worker has finished.
+        let total = bytes.load(Ordering::Relaxed);
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(bytes.load(Ordering::Relaxed), total);
+        assert!(child.check().is_err());
+        assert!(ct.check().is_ok());
+
+        let handle = ct.scope_spawn(|_| panic!("worker"));
+        assert!(handle.join().is_err());
+    }
+
+    #[test]
+    fn test_spawn_watchdog() {
+        let ct = CancellationToken::new();
+        let polls = Arc::new(AtomicU64::new(0));
+        let _watchdog = ct.spawn_watchdog(
+            {
+                let polls = polls.clone();
+                move || polls.fetch_add(1, Ordering::Relaxed) >= 3
+            },
+            Duration::from_millis(5),
+        );
+        while ct.check().is_ok() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(ct.cancel_cause(), Some(CancelCause::Watchdog));
+        assert_eq!(polls.load(Ordering::Relaxed), 4);
+
+        // Stopped early.
+        let ct = CancellationToken::new();
+        let watchdog = ct.spawn_watchdog(|| false, Duration::from_secs(100));
+        watchdog.stop();
+        assert!(ct.check().is_ok());
+    }
+
+    #[test]
+    fn test_cancelled_or() {
+        let ct = CancellationToken::new();
+        let start = Instant::now();
+        assert_eq!(
+            ct.cancelled_or(Duration::from_millis(20)),
+            CancelResult::TimedOut
+        );
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let th = std::thread::spawn({
+            let ct = ct.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(10));
+                ct.cancel();
+            }
+        });
+        assert_eq!(
+            ct.cancelled_or(Duration::from_secs(60)),
+            CancelResult::Cancelled
+        );
+        th.join().unwrap();
+        assert_eq!(ct.cancelled_or(Duration::ZERO), CancelResult::Cancelled);
+    }
+
+    #[test]
+    fn test_wait() {
+        let ct = CancellationToken::new();
+        assert!(!ct.wait_timeout(Duration::from_millis(10)));
+        let th = std::thread::spawn({
+            let ct = ct.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(10));
+                ct.cancel();
+            }
+        });
+        ct.wait();
+        assert!(ct.check().is_err());
+        th.join().unwrap();
+        ct.wait();
+        assert!(ct.wait_timeout(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_deadline() {
+        assert_eq!(CancellationToken::new().deadline(), None);
+
+        let ct = CancellationToken::new();
+        let deadline = Instant::now() + Duration::from_secs(60);
+        ct.cancel_at(deadline);
+        assert_eq!(ct.deadline(), Some(deadline));
+        // The earliest one is kept.
+        ct.cancel_at(deadline + Duration::from_secs(1));
+        assert_eq!(ct.deadline(), Some(deadline));
+        ct.cancel_at(deadline - Duration::from_secs(1));
+        assert_eq!(ct.deadline(), Some(deadline - Duration::from_secs(1)));
+        ct.cancel();
+        assert_eq!(ct.deadline(), Some(deadline - Duration::from_secs(1)));
+
+        let guard = CancellationToken::timeout_guard(Duration::from_secs(60));
+        assert!(guard.token().deadline().unwrap() > Instant::now());
     }
-}
 
-impl Eq for CancellationToken {}
+    #[test]
+    fn test_cancel_cause() {
+        let ct = CancellationToken::new();
+        assert_eq!(ct.cancel_cause(), None);
+        ct.cancel();
+        assert_eq!(ct.cancel_cause(), Some(CancelCause::Manual));
+        ct.cancel_after(Duration::ZERO);
+        assert_eq!(ct.cancel_cause(), Some(CancelCause::Manual));
 
-impl Ord for CancellationToken {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.cancelled.as_ptr().cmp(&other.cancelled.as_ptr())
-    }
-}
+        let ct = CancellationToken::new();
+        ct.cancel_after(Duration::from_millis(10));
+        assert_eq!(ct.cancel_cause(), None);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(ct.cancel_cause(), Some(CancelCause::Deadline));
 
-impl PartialOrd for CancellationToken {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
-}
+        let parent = CancellationToken::new();
+        let child = parent.child();
+        let linked = CancellationToken::new();
+        linked.depends_on(&child);
+        parent.cancel();
+        assert_eq!(child.cancel_cause(), Some(CancelCause::Parent(parent.id())));
+        assert_eq!(linked.cancel_cause(), Some(CancelCause::Linked(child.id())));
 
-impl std::hash::Hash for CancellationToken {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.cancelled.as_ptr().hash(state);
+        let ct = CancellationToken::new();
+        drop(CancellationGuard(ct.clone()));
+        assert_eq!(ct.cancel_cause(), Some(CancelCause::GuardDrop));
     }
-}
 
-impl CancellationToken {
-    /// Creates a new `CancellationToken`, in a non-cancelled state.
-    pub fn new() -> Self {
-        Self::default()
+    #[test]
+    fn test_reason() {
+        let parent = CancellationToken::new();
+        let child = parent.child();
+        assert_eq!(parent.reason(), None);
+        parent.cancel_with_reason("timeout");
+        parent.cancel_with_reason("ignored");
+        assert_eq!(parent.reason().as_deref(), Some("timeout"));
+        assert_eq!(child.reason().as_deref(), Some("timeout"));
+        assert_eq!(parent.child().reason().as_deref(), Some("timeout"));
     }
-    /// Signals this token as _cancelled_.
-    ///
-    /// Note that it takes a non-mutable `self`, so you are able to cancel a
-    /// shared token.
-    pub fn cancel(&self) {
-        self.cancelled.store(true, Ordering::Relaxed);
+
+    #[test]
+    fn test_cancel_with_error() {
+        let ct = CancellationToken::new();
+        let child = ct.child();
+        let mut r = Cancellable::new(io::repeat(0), child.clone());
+        ct.cancel_with_error(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "access revoked",
+        ));
+        let err = r.read(&mut [0; 4]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            classify(&err),
+            Outcome::Cancelled(Some("access revoked".to_string()))
+        );
+        assert_eq!(err.to_string(), "operation cancelled: access revoked");
+        let err = ct.child().check().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        assert!(ct.reset_if(true));
+        ct.cancel();
+        assert_eq!(ct.check().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
     }
-    /// Checks whether a token is cancelled.
-    ///
-    /// It returns `Ok(())` if non-cancelled, `Err(ErrorKind::BrokenPipe)` if cancelled.
-    pub fn check(&self) -> std::io::Result<()> {
-        let cancelled = self.cancelled.load(Ordering::Relaxed);
-        if cancelled {
-            Err(std::io::ErrorKind::BrokenPipe.into())
-        } else {
-            Ok(())
+
+    #[test]
+    fn test_cancel_and_reason_once() {
+        for _ in 0..20 {
+            let ct = CancellationToken::new();
+            let barrier = Arc::new(std::sync::Barrier::new(8));
+            let threads: Vec<_> = (0..8)
+                .map(|i| {
+                    let ct = ct.clone();
+                    let barrier = barrier.clone();
+                    std::thread::spawn(move || {
+                        barrier.wait();
+                        ct.cancel_and_reason_once(format!("thread {i}"))
+                    })
+                })
+                .collect();
+            let winners: Vec<_> = threads
+                .into_iter()
+                .map(|th| th.join().unwrap())
+                .enumerate()
+                .filter(|(_, won)| *won)
+                .collect();
+            assert_eq!(winners.len(), 1);
+            let expected = format!("thread {}", winners[0].0);
+            assert_eq!(ct.reason(), Some(expected));
         }
+        let ct = CancellationToken::new();
+        ct.cancel();
+        assert!(!ct.cancel_and_reason_once("late"));
+        assert_eq!(ct.reason(), None);
     }
-}
 
-/// A newtype around `CancellationToken` that automatically cancels on `drop`.
-pub struct CancellationGuard(pub CancellationToken);
+    #[test]
+    fn test_from_atomic() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let ct = CancellationToken::from_atomic(flag.clone());
+        let mut r = Cancellable::new(io::repeat(0), ct.clone());
+        let mut buf = [0; 4];
+        assert_eq!(r.read(&mut buf).unwrap(), 4);
+        flag.store(true, Ordering::Relaxed);
+        let err = r.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
 
-impl Drop for CancellationGuard {
-    fn drop(&mut self) {
-        self.0.cancel();
+        flag.store(false, Ordering::Relaxed);
+        assert!(ct.check().is_ok());
+        ct.cancel();
+        assert!(flag.load(Ordering::Relaxed));
     }
-}
 
-/// A newtype around any `Read`, `Write` or `Seek` value, that makes it cancellable.
-pub struct Cancellable<T> {
-    inner: T,
-    token: CancellationToken,
-}
+    #[test]
+    fn test_reset_if() {
+        let ct = CancellationToken::new();
+        assert!(!ct.reset_if(true));
+        assert!(ct.reset_if(false));
+        assert!(ct.check().is_err());
+        assert!(!ct.reset_if(false));
+        assert!(ct.reset_if(true));
+        assert!(ct.check().is_ok());
+        assert_eq!(ct.cancel_cause(), None);
 
-impl<T> Cancellable<T> {
-    /// Wraps a value as `Cancellable`.
-    pub fn new(inner: T, token: CancellationToken) -> Self {
-        Self { inner, token }
-    }
-    /// Gets the inner token.
-    ///
-    /// You will probably need to clone it if you want store it somewhere.
-    pub fn token(&self) -> &CancellationToken {
-        &self.token
-    }
-    /// Unwraps the inner value.
-    pub fn into_inner(self) -> T {
-        self.inner
-    }
-    /// Gets a reference to the inner value.
-    pub fn get_ref(&self) -> &T {
-        &self.inner
-    }
-    /// Gets a mutable reference to the inner value.
-    pub fn get_mut(&mut self) -> &mut T {
-        &mut self.inner
+        for expect_cancelled in [true, false] {
+            let ct = CancellationToken::new();
+            if expect_cancelled {
+                ct.cancel();
+            }
+            let barrier = Arc::new(std::sync::Barrier::new(8));
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    let ct = ct.clone();
+                    let barrier = barrier.clone();
+                    std::thread::spawn(move || {
+                        barrier.wait();
+                        ct.reset_if(expect_cancelled)
+                    })
+                })
+                .collect();
+            let ok = threads
+                .into_iter()
+                .map(|th| th.join().unwrap())
+                .filter(|ok| *ok)
+                .count();
+            assert_eq!(ok, 1);
+            assert_eq!(ct.check().is_err(), !expect_cancelled);
+        }
     }
-}
 
-impl<T: std::io::Read> std::io::Read for Cancellable<T> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.token.check()?;
-        self.inner.read(buf)
+    #[test]
+    fn test_cancel_children() {
+        let parent = CancellationToken::new();
+        let children: Vec<_> = (0..3).map(|_| parent.child()).collect();
+        let grandchild = children[0].child();
+        let linked = CancellationToken::new();
+        linked.depends_on(&parent);
+        parent.cancel_children();
+        assert!(parent.check().is_ok());
+        assert!(linked.check().is_ok());
+        assert!(children.iter().all(|c| c.check().is_err()));
+        assert!(grandchild.check().is_err());
+        assert_eq!(
+            children[1].cancel_cause(),
+            Some(CancelCause::Parent(parent.id()))
+        );
+        assert!(parent.child().check().is_ok());
     }
 
-    fn read_vectored(&mut self, bufs: &mut [std::io::IoSliceMut<'_>]) -> std::io::Result<usize> {
-        self.token.check()?;
-        self.inner.read_vectored(bufs)
+    #[test]
+    fn test_cancel_children_keeps_watchdog() {
+        let ct = CancellationToken::new();
+        let fire = Arc::new(AtomicBool::new(false));
+        let _watchdog = ct.spawn_watchdog(
+            {
+                let fire = fire.clone();
+                move || fire.load(Ordering::Relaxed)
+            },
+            Duration::from_millis(1),
+        );
+        ct.cancel_children();
+        fire.store(true, Ordering::Relaxed);
+        assert_eq!(
+            ct.cancelled_or(Duration::from_secs(60)),
+            CancelResult::Cancelled
+        );
+        assert_eq!(ct.cancel_cause(), Some(CancelCause::Watchdog));
     }
 
-    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
-        self.token.check()?;
-        self.inner.read_to_end(buf)
-    }
+    #[test]
+    fn test_all() {
+        let a = CancellationToken::new();
+        let b = CancellationToken::new();
+        let c = CancellationToken::new();
+        let all = CancellationToken::all([&a, &b, &c]);
+        assert!(all.check().is_ok());
+        a.cancel();
+        b.cancel();
+        assert!(all.check().is_ok());
+        c.cancel_with_reason("done");
+        assert!(all.check().is_err());
+        assert_eq!(all.cancel_cause(), Some(CancelCause::Linked(c.id())));
+        assert_eq!(all.reason().as_deref(), Some("done"));
 
-    fn read_to_string(&mut self, buf: &mut String) -> std::io::Result<usize> {
-        self.token.check()?;
-        self.inner.read_to_string(buf)
-    }
+        // Already cancelled sources.
+        assert!(CancellationToken::all([&a, &b]).check().is_err());
+        assert!(CancellationToken::all([&a, &CancellationToken::new()])
+            .check()
+            .is_ok());
+        assert!(CancellationToken::all([]).check().is_err());
 
-    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
-        self.token.check()?;
-        self.inner.read_exact(buf)
+        // Concurrent cancellations of the last sources.
+        for _ in 0..100 {
+            let a = CancellationToken::new();
+            let b = CancellationToken::new();
+            let all = CancellationToken::all([&a, &b]);
+            let th = std::thread::spawn(move || a.cancel());
+            b.cancel();
+            th.join().unwrap();
+            assert!(all.check().is_err());
+        }
     }
-}
 
-impl<T: std::io::Write> std::io::Write for Cancellable<T> {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.token.check()?;
-        self.inner.write(buf)
+    #[test]
+    fn test_child() {
+        let parent = CancellationToken::new();
+        let child = parent.child();
+        child.cancel();
+        assert!(parent.check().is_ok());
+        let child = parent.child();
+        parent.cancel();
+        assert!(child.check().is_err());
+        assert!(parent.child().check().is_err());
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.token.check()?;
-        self.inner.flush()
-    }
-    fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
-        self.token.check()?;
-        self.inner.write_vectored(bufs)
+    #[test]
+    fn test_with_parent() {
+        let parent = CancellationToken::new();
+        let child = CancellationToken::with_parent(&parent);
+        let sibling = CancellationToken::with_parent(&parent);
+        child.cancel();
+        assert!(parent.check().is_ok());
+        assert!(sibling.check().is_ok());
+        parent.cancel();
+        assert!(sibling.check().is_err());
+        assert_eq!(
+            sibling.cancel_cause(),
+            Some(CancelCause::Parent(parent.id()))
+        );
+        assert!(CancellationToken::with_parent(&parent).check().is_err());
     }
 
-    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        self.token.check()?;
-        self.inner.write_all(buf)
-    }
+    #[test]
+    fn test_tee() {
+        let ct = CancellationToken::new();
+        let teed = ct.tee();
+        assert_ne!(teed, ct);
+        assert!(teed.check().is_ok());
+        ct.cancel();
+        assert!(teed.check().is_ok());
 
-    fn write_fmt(&mut self, fmt: std::fmt::Arguments<'_>) -> std::io::Result<()> {
-        self.token.check()?;
-        self.inner.write_fmt(fmt)
+        let teed = ct.tee();
+        assert!(teed.check().is_err());
+        assert_eq!(teed.cancel_cause(), Some(CancelCause::Manual));
+        assert!(teed.reset_if(true));
+        assert!(ct.check().is_err());
+
+        let ct = CancellationToken::new();
+        ct.cancel_with_reason("stop");
+        let teed = ct.tee();
+        assert_eq!(teed.reason().as_deref(), Some("stop"));
+        assert!(ct.reset_if(true));
+        assert!(teed.check().is_err());
     }
-}
 
-impl<T: std::io::Seek> std::io::Seek for Cancellable<T> {
-    fn seek(&mut self, from: std::io::SeekFrom) -> std::io::Result<u64> {
-        self.token.check()?;
-        self.inner.seek(from)
+    #[test]
+    fn test_reason_mapper() {
+        let parent = CancellationToken::new();
+        let child = parent.child().with_reason_mapper(|r| {
+            Some(format!("upload aborted: {}", r.as_deref().unwrap_or("?")))
+        });
+        let grandchild = child.child();
+        parent.cancel_with_reason("shutdown");
+        assert_eq!(parent.reason().as_deref(), Some("shutdown"));
+        assert_eq!(child.reason().as_deref(), Some("upload aborted: shutdown"));
+        assert_eq!(
+            grandchild.reason().as_deref(),
+            Some("upload aborted: shutdown")
+        );
+
+        // Not used for a direct cancellation.
+        let child = CancellationToken::new()
+            .child()
+            .with_reason_mapper(|_| None);
+        child.cancel_with_reason("direct");
+        assert_eq!(child.reason().as_deref(), Some("direct"));
     }
 
-    fn rewind(&mut self) -> std::io::Result<()> {
-        self.token.check()?;
-        self.inner.rewind()
+    #[test]
+    fn test_keyed() {
+        use std::collections::HashSet;
+
+        let a = CancellationToken::with_key("job-1".to_string());
+        let b = CancellationToken::with_key("job-1".to_string());
+        let c = CancellationToken::with_key("job-2".to_string());
+        let plain = CancellationToken::new();
+        assert_eq!(a.key(), Some("job-1"));
+        assert_eq!(plain.key(), None);
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_ne!(a, plain);
+        assert_eq!(plain, plain.clone());
+        assert!(a < c);
+        assert!(plain < a);
+
+        let set: HashSet<_> = [b, a.clone(), c, plain.clone(), plain.clone()].into();
+        assert_eq!(set.len(), 3);
+        assert!(set.contains(&CancellationToken::with_key("job-2".to_string())));
+        assert!(!set.contains(&CancellationToken::new()));
+
+        a.cancel();
+        assert!(set.get(&a).unwrap().check().is_ok());
     }
 
-    fn stream_position(&mut self) -> std::io::Result<u64> {
-        self.token.check()?;
-        self.inner.stream_position()
+    #[test]
+    fn test_snapshot() {
+        let ct = CancellationToken::new();
+        let snap = ct.snapshot();
+        assert_eq!(
+            snap,
+            TokenSnapshot {
+                cancelled: false,
+                id: ct.id(),
+                reason: None,
+                cancelled_at: None,
+            }
+        );
+        let before = Instant::now();
+        ct.cancel_with_reason("stop");
+        let snap = ct.snapshot();
+        assert!(snap.cancelled);
+        assert_eq!(snap.id, ct.id());
+        assert_eq!(snap.reason.as_deref(), Some("stop"));
+        let at = snap.cancelled_at.unwrap();
+        assert!(at >= before && at <= Instant::now());
+
+        assert!(ct.reset_if(true));
+        assert_eq!(ct.snapshot().cancelled_at, None);
     }
 
-    fn seek_relative(&mut self, offset: i64) -> std::io::Result<()> {
-        self.token.check()?;
-        self.inner.seek_relative(offset)
+    #[test]
+    fn test_label() {
+        let ct = CancellationToken::with_label("upload");
+        assert_eq!(ct.label(), Some("upload"));
+        assert_eq!(CancellationToken::new().label(), None);
+        assert_eq!(ct.to_string(), "upload");
+        assert!(format!("{ct:?}").contains("\"upload\""));
+        ct.cancel();
+        assert_eq!(ct.to_string(), "upload (cancelled)");
+        // It does not affect the equality.
+        assert_ne!(ct, CancellationToken::with_label("upload"));
+
+        let plain = CancellationToken::new();
+        assert_eq!(plain.to_string(), format!("token {:#x}", plain.id()));
     }
-}
 
-impl<T: std::io::BufRead> std::io::BufRead for Cancellable<T> {
-    // Provided methods are not wrapped, probably not worth it
-    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
-        self.token.check()?;
-        self.inner.fill_buf()
+    #[test]
+    fn test_ord() {
+        use std::cmp::Ordering::*;
+        use std::collections::BTreeMap;
+
+        let plain: Vec<_> = (0..4).map(|_| CancellationToken::new()).collect();
+        let mut tokens = plain.clone();
+        tokens.extend(plain.iter().cloned());
+        for key in ["b", "a", "c", "a"] {
+            tokens.push(CancellationToken::with_key(key.to_string()));
+        }
+        for a in &tokens {
+            assert_eq!(a.cmp(a), Equal);
+            for b in &tokens {
+                assert_eq!(a.cmp(b), b.cmp(a).reverse());
+                assert_eq!(a.cmp(b) == Equal, a == b);
+                assert_eq!(a.partial_cmp(b), Some(a.cmp(b)));
+                for c in &tokens {
+                    if a <= b && b <= c {
+                        assert!(a <= c);
+                    }
+                }
+            }
+        }
+
+        let mut map = BTreeMap::new();
+        for (i, t) in tokens.iter().enumerate() {
+            map.insert(t.clone(), i);
+        }
+        assert_eq!(map.len(), 7);
+        assert_eq!(map[&plain[1]], 5);
+        assert_eq!(map[&CancellationToken::with_key("a".to_string())], 11);
+        let keys: Vec<_> = map.keys().filter_map(|t| t.key()).collect();
+        assert_eq!(keys, ["a", "b", "c"]);
     }
-    fn consume(&mut self, amt: usize) {
-        self.inner.consume(amt)
+
+    #[test]
+    fn test_cancellable_take() {
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(io::repeat(1), ct.clone()).cancellable_take(10);
+        let mut data = [0; 4];
+        assert_eq!(r.read(&mut data).unwrap(), 4);
+        let mut rest = Vec::new();
+        assert_eq!(r.read_to_end(&mut rest).unwrap(), 6);
+        assert_eq!(r.get_ref().limit(), 0);
+
+        r.get_mut().set_limit(10);
+        r.token().cancel();
+        let err = r.read(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(r.get_ref().limit(), 10);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::io::{self, Read, Seek, Write};
-    use std::time::Duration;
+    #[test]
+    fn test_raw() {
+        let ct = CancellationToken::new();
+        let ptr = ct.clone().into_raw();
+        let cancel: unsafe extern "C" fn(*const ()) = CancellationToken::cancel_raw;
+        unsafe { cancel(ptr) };
+        assert!(ct.check().is_err());
 
-    fn inf_write(ct: CancellationToken) -> io::Result<()> {
-        let w = io::empty();
-        let mut w = Cancellable::new(w, ct);
-        for _i in 0..10 {
-            w.write_all(&[0])?;
-            std::thread::sleep(Duration::from_millis(100));
-        }
-        Ok(())
+        let back = unsafe { CancellationToken::from_raw(ptr) };
+        assert_eq!(back, ct);
+        assert!(back.check().is_err());
     }
 
-    fn inf_read(ct: CancellationToken) -> io::Result<()> {
-        let r = io::empty();
-        let mut r = Cancellable::new(r, ct);
-        let mut data = [0];
-        for _i in 0..10 {
-            r.read(&mut data)?;
-            std::thread::sleep(Duration::from_millis(100));
-        }
-        Ok(())
+    #[test]
+    fn test_bool_sender() {
+        let ct = CancellationToken::new();
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+        ct.bool_sender(tx.clone());
+        // The channel is full, this one is dropped.
+        ct.bool_sender(tx);
+        assert!(rx.try_recv().is_err());
+        ct.cancel();
+        assert_eq!(rx.try_recv(), Ok(()));
+        assert!(rx.try_recv().is_err());
+
+        // A closed channel is ignored.
+        let ct = CancellationToken::new();
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+        ct.bool_sender(tx);
+        drop(rx);
+        ct.cancel();
+        assert!(ct.check().is_err());
     }
 
-    fn inf_seek(ct: CancellationToken) -> io::Result<()> {
-        let r = io::empty();
-        let mut r = Cancellable::new(r, ct);
-        for _i in 0..10 {
-            r.seek(io::SeekFrom::Start(0))?;
-            std::thread::sleep(Duration::from_millis(100));
+    #[test]
+    fn test_on_cancel() {
+        let ct = CancellationToken::with_capacity(1000);
+        let count = Arc::new(AtomicU64::new(0));
+        for _ in 0..1000 {
+            let count = count.clone();
+            ct.on_cancel(move || {
+                count.fetch_add(1, Ordering::Relaxed);
+            });
         }
-        Ok(())
+        assert_eq!(ct.inner.callbacks.lock().unwrap().0.capacity(), 1000);
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        ct.child().cancel();
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        ct.cancel();
+        assert_eq!(count.load(Ordering::Relaxed), 1000);
+        let callbacks = ct.inner.callbacks.lock().unwrap();
+        assert!(callbacks.0.is_empty());
+        assert_eq!(callbacks.0.capacity(), 1000);
+        drop(callbacks);
+
+        // Already cancelled, called immediately.
+        let c = count.clone();
+        ct.on_cancel(move || {
+            c.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(count.load(Ordering::Relaxed), 1001);
     }
 
+    #[cfg(feature = "debug-strong-count")]
     #[test]
-    fn test_write() {
+    fn test_strong_count() {
         let ct = CancellationToken::new();
-        let th = std::thread::spawn({
-            let ct = ct.clone();
-            move || {
-                inf_write(ct).unwrap();
-            }
-        });
-        ct.cancel();
-        let err = th.join().unwrap_err();
-        let err = err.downcast::<String>().unwrap();
-        assert!(err.contains("BrokenPipe"));
+        assert_eq!(ct.strong_count(), 1);
+        let clones: Vec<_> = (0..5).map(|_| ct.clone()).collect();
+        assert_eq!(ct.strong_count(), 6);
+        let child = ct.child();
+        assert_eq!(ct.strong_count(), 6);
+        assert_eq!(child.strong_count(), 1);
+        drop(clones);
+        assert_eq!(ct.strong_count(), 1);
+        let linked = CancellationToken::new();
+        linked.depends_on(&ct);
+        assert_eq!(ct.strong_count(), 1);
+    }
+
+    #[cfg(feature = "debug-strong-count")]
+    #[test]
+    fn test_strong_count_all() {
+        let a = CancellationToken::new();
+        let all = CancellationToken::all([&a]);
+        assert_eq!(a.strong_count(), 2);
+        drop(all);
+        assert_eq!(a.strong_count(), 1);
     }
 
     #[test]
@@ -272,6 +4537,68 @@ mod test {
         assert!(err.contains("BrokenPipe"));
     }
 
+    #[test]
+    fn test_timeout_guard_deadline() {
+        let guard = CancellationToken::timeout_guard(Duration::from_millis(20));
+        let ct = guard.token().clone();
+        let mut r = Cancellable::new(io::repeat(0), ct.clone());
+        let mut data = [0; 4];
+        let err = loop {
+            if let Err(e) = r.read(&mut data) {
+                break e;
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(ct.cancel_cause(), Some(CancelCause::Deadline));
+        drop(guard);
+        assert_eq!(ct.cancel_cause(), Some(CancelCause::Deadline));
+    }
+
+    #[test]
+    fn test_timeout_guard_drop() {
+        let ct = {
+            let guard = CancellationToken::timeout_guard(Duration::from_secs(60));
+            let ct = guard.token().clone();
+            assert!(ct.check().is_ok());
+            ct
+        };
+        assert!(ct.check().is_err());
+        assert_eq!(ct.cancel_cause(), Some(CancelCause::GuardDrop));
+    }
+
+    #[test]
+    fn test_merge_into_guard() {
+        let tokens: Vec<_> = (0..3).map(|_| CancellationToken::new()).collect();
+        let guard = tokens[0].merge_into_guard(&tokens[1..]);
+        assert!(guard.0.check().is_ok());
+        assert!(tokens.iter().all(|t| t.check().is_ok()));
+        drop(guard);
+        assert!(tokens.iter().all(|t| t.check().is_err()));
+
+        // Any of them cancels the rest.
+        let tokens: Vec<_> = (0..3).map(|_| CancellationToken::new()).collect();
+        let guard = tokens[0].merge_into_guard(&tokens[1..]);
+        tokens[2].cancel();
+        assert!(guard.0.check().is_err());
+        assert!(tokens.iter().all(|t| t.check().is_err()));
+        assert_eq!(tokens[2].cancel_cause(), Some(CancelCause::Manual));
+        assert_eq!(
+            tokens[0].cancel_cause(),
+            Some(CancelCause::Linked(guard.0.id()))
+        );
+    }
+
+    #[test]
+    fn test_from_guard() {
+        let guard = CancellationGuard(CancellationToken::new());
+        let mut r = Cancellable::from_guard(io::repeat(0), &guard);
+        let mut data = [0; 4];
+        assert_eq!(r.read(&mut data).unwrap(), 4);
+        drop(guard);
+        let err = r.read(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
     #[test]
     fn test_read() {
         let ct = CancellationToken::new();
@@ -287,6 +4614,124 @@ mod test {
         assert!(err.contains("BrokenPipe"));
     }
 
+    // A seekable stream that cancels the token when seeking to the end.
+    struct CancelOnSeekEnd(io::Cursor<Vec<u8>>, CancellationToken);
+
+    impl Seek for CancelOnSeekEnd {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            if let io::SeekFrom::End(_) = pos {
+                self.1.cancel();
+            }
+            self.0.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_length() {
+        let ct = CancellationToken::new();
+        let inner = CancelOnSeekEnd(io::Cursor::new(vec![0; 10]), ct.clone());
+        let mut s = Cancellable::new(inner, ct);
+        s.seek(io::SeekFrom::Start(3)).unwrap();
+        assert_eq!(s.length().unwrap(), 10);
+        assert_eq!(s.get_ref().0.position(), 3);
+        let err = s.length().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(s.get_ref().0.position(), 3);
+    }
+
+    #[test]
+    fn test_seek_checked() {
+        let ct = CancellationToken::new();
+        let inner = CancelOnSeekEnd(io::Cursor::new(vec![0; 10]), ct.clone());
+        let mut s = Cancellable::new(inner, ct);
+        assert_eq!(s.seek_checked(io::SeekFrom::Start(3)).unwrap(), 3);
+        assert_eq!(s.seek_checked(io::SeekFrom::Current(2)).unwrap(), 5);
+        let err = s.seek_checked(io::SeekFrom::Current(-6)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(s.get_ref().0.position(), 5);
+        // Cancelled in the middle of the seek, rolled back.
+        let err = s.seek_checked(io::SeekFrom::End(-1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(s.get_ref().0.position(), 5);
+        assert!(s.seek_checked(io::SeekFrom::Start(0)).is_err());
+        assert_eq!(s.get_ref().0.position(), 5);
+
+        // Validated against the length, the inner value would not fail.
+        let mut s = Cancellable::new(
+            Clamping(io::Cursor::new(vec![0; 10])),
+            CancellationToken::new(),
+        );
+        s.seek_checked(io::SeekFrom::Start(5)).unwrap();
+        let err = s.seek_checked(io::SeekFrom::End(-11)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(s.get_ref().0.position(), 5);
+        assert_eq!(s.seek_checked(io::SeekFrom::End(-10)).unwrap(), 0);
+    }
+
+    // A stream that moves to the start on a seek before it, instead of failing.
+    struct Clamping(io::Cursor<Vec<u8>>);
+
+    impl Seek for Clamping {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            let len = self.0.get_ref().len() as u64;
+            let pos = match pos {
+                io::SeekFrom::End(offset) => {
+                    io::SeekFrom::Start(len.checked_add_signed(offset).unwrap_or(0))
+                }
+                pos => pos,
+            };
+            self.0.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_verify_position() {
+        let ct = CancellationToken::new();
+        let mut s = Cancellable::new(io::Cursor::new(b"0123456789".to_vec()), ct.clone());
+        let mut buf = [0; 5];
+        s.read_exact(&mut buf).unwrap();
+        ct.cancel();
+        assert!(s.read(&mut buf).is_err());
+        s.verify_position(5).unwrap();
+
+        // The source shrank before the transfer is resumed at its end.
+        *s.get_mut() = io::Cursor::new(b"012".to_vec());
+        s.get_mut().seek(io::SeekFrom::End(0)).unwrap();
+        let err = s.verify_position(5).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "stream position is 3, expected 5");
+    }
+
+    #[test]
+    fn test_replace_token() {
+        let ct = CancellationToken::new();
+        let mut s = Cancellable::new(io::Cursor::new(b"0123456789".to_vec()), ct.clone()).fuse();
+        let mut buf = [0; 3];
+        s.read_exact(&mut buf).unwrap();
+        assert_eq!(s.current_position().unwrap(), 3);
+        ct.cancel();
+        assert!(s.current_position().is_err());
+        assert!(s.read(&mut buf).is_err());
+
+        // Still fused with the new token, until the fuse is reset.
+        let old = s.replace_token(CancellationToken::new());
+        assert_eq!(old, ct);
+        assert!(s.current_position().is_err());
+        assert!(s.read(&mut buf).is_err());
+        s.reset_fuse();
+        assert_eq!(s.current_position().unwrap(), 3);
+        s.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"345");
+        assert_eq!(s.current_position().unwrap(), 6);
+
+        // Not fused, the new token is used right away.
+        let mut s = Cancellable::new(io::Cursor::new(b"012".to_vec()), ct.clone());
+        assert!(s.read(&mut buf).is_err());
+        s.replace_token(CancellationToken::new());
+        s.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"012");
+    }
+
     #[test]
     fn test_seek() {
         let ct = CancellationToken::new();
@@ -0,0 +1,134 @@
+use crate::Cancellable;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Statistics collected by a `Cancellable` when the `metrics` feature is enabled.
+#[derive(Default, Debug)]
+pub(crate) struct Metrics {
+    sizes: [AtomicU64; 32],
+    bytes: AtomicU64,
+    ops: AtomicU64,
+    aborts: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn record_size(&self, n: usize) {
+        let bucket = (usize::BITS - n.leading_zeros()).min(31) as usize;
+        self.sizes[bucket].fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        self.ops.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn record_abort(&self) {
+        self.aborts.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Statistics of a `Cancellable`.
+///
+/// See [`Cancellable::stats`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Total number of bytes returned by the `read` and `write` calls to the
+    /// inner value.
+    pub bytes: u64,
+    /// Number of `read` and `write` calls to the inner value.
+    pub ops: u64,
+    /// Number of operations that failed because the token was cancelled.
+    pub aborts: u64,
+}
+
+impl<T> Cancellable<T> {
+    // The metrics are in the options, that are always set with this feature.
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self
+            .options
+            .as_ref()
+            .expect("metrics are always set")
+            .metrics
+    }
+    /// Gets a histogram of the sizes returned by the `read` and `write` calls
+    /// to the inner value.
+    ///
+    /// Bucket 0 counts the calls that returned 0 bytes, and bucket `i`, for
+    /// `i > 0`, those that returned between `2^(i-1)` and `2^i - 1` bytes.
+    /// The last bucket also counts any bigger size.
+    ///
+    /// This is useful to choose a good size for [`Cancellable::chunked`].
+    pub fn size_histogram(&self) -> [u64; 32] {
+        std::array::from_fn(|i| self.metrics().sizes[i].load(Ordering::Relaxed))
+    }
+    /// Gets the statistics collected so far.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            bytes: self.metrics().bytes.load(Ordering::Relaxed),
+            ops: self.metrics().ops.load(Ordering::Relaxed),
+            aborts: self.metrics().aborts.load(Ordering::Relaxed),
+        }
+    }
+    /// Clears all the statistics, including the histogram.
+    ///
+    /// The token is not affected. This is useful for values that are reused,
+    /// such as pooled connections, to get the statistics of each request.
+    pub fn reset_stats(&self) {
+        let m = self.metrics();
+        for bucket in &m.sizes {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        m.bytes.store(0, Ordering::Relaxed);
+        m.ops.store(0, Ordering::Relaxed);
+        m.aborts.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Cancellable, CancellationToken, Stats};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_size_histogram() {
+        let mut r = Cancellable::new(&[0; 100][..], CancellationToken::new());
+        for n in [1, 2, 3, 4, 7, 8, 64] {
+            assert_eq!(r.read(&mut vec![0; n]).unwrap(), n);
+        }
+        assert_eq!(r.read(&mut [0; 100]).unwrap(), 11);
+        assert_eq!(r.read(&mut [0; 100]).unwrap(), 0);
+        let mut expected = [0; 32];
+        expected[0] = 1;
+        expected[1] = 1;
+        expected[2] = 2;
+        expected[3] = 2;
+        expected[4] = 2;
+        expected[7] = 1;
+        assert_eq!(r.size_histogram(), expected);
+
+        let mut w = Cancellable::new(Vec::new(), CancellationToken::new()).chunked(1000);
+        w.write_all(&[0; 5000]).unwrap();
+        assert_eq!(w.write(&[]).unwrap(), 0);
+        let mut expected = [0; 32];
+        expected[0] = 1;
+        expected[10] = 5;
+        assert_eq!(w.size_histogram(), expected);
+    }
+
+    #[test]
+    fn test_reset_stats() {
+        let ct = CancellationToken::new();
+        let mut w = Cancellable::new(Vec::new(), ct.clone());
+        assert_eq!(w.write(b"hello").unwrap(), 5);
+        assert_eq!(w.write(b"!!").unwrap(), 2);
+        ct.cancel();
+        assert!(w.write(b"?").is_err());
+        assert_eq!(
+            w.stats(),
+            Stats {
+                bytes: 7,
+                ops: 2,
+                aborts: 1
+            }
+        );
+        w.reset_stats();
+        assert_eq!(w.stats(), Stats::default());
+        assert_eq!(w.size_histogram(), [0; 32]);
+        assert!(w.token().check().is_err());
+    }
+}
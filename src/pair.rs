@@ -0,0 +1,85 @@
+use crate::CancellationToken;
+
+/// The half of a token pair that can cancel.
+///
+/// It is created with [`CancellationToken::new_pair`].
+#[derive(Clone, Debug)]
+pub struct Canceller(CancellationToken);
+
+/// The half of a token pair that can only watch for the cancellation.
+///
+/// It is created with [`CancellationToken::new_pair`]. Workers that are
+/// given a `CancelWatcher` cannot cancel by accident, because it has no
+/// method to do that:
+///
+/// ```compile_fail
+/// let (_canceller, watcher) = cancel_rw::CancellationToken::new_pair();
+/// watcher.cancel();
+/// ```
+#[derive(Clone, Debug)]
+pub struct CancelWatcher(CancellationToken);
+
+impl CancellationToken {
+    /// Creates a new token split in a `Canceller` and a `CancelWatcher`.
+    ///
+    /// Both share the same state, but only the `Canceller` can cancel it and
+    /// only the `CancelWatcher` can check it.
+    pub fn new_pair() -> (Canceller, CancelWatcher) {
+        let token = CancellationToken::new();
+        (Canceller(token.clone()), CancelWatcher(token))
+    }
+}
+
+impl Canceller {
+    /// Cancels the token.
+    ///
+    /// See [`CancellationToken::cancel`].
+    pub fn cancel(&self) {
+        self.0.cancel();
+    }
+    /// Cancels the token, with a reason.
+    ///
+    /// See [`CancellationToken::cancel_with_reason`].
+    pub fn cancel_with_reason(&self, reason: impl Into<String>) {
+        self.0.cancel_with_reason(reason);
+    }
+}
+
+impl CancelWatcher {
+    /// Checks whether the token is cancelled.
+    ///
+    /// See [`CancellationToken::check`].
+    pub fn check(&self) -> std::io::Result<()> {
+        self.0.check()
+    }
+    /// Blocks until the token is cancelled.
+    ///
+    /// See [`CancellationToken::wait`].
+    pub fn wait(&self) {
+        self.0.wait()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pair() {
+        let (canceller, watcher) = CancellationToken::new_pair();
+        assert!(watcher.check().is_ok());
+        let th = std::thread::spawn({
+            let watcher = watcher.clone();
+            move || {
+                watcher.wait();
+                watcher.check().unwrap_err()
+            }
+        });
+        canceller.cancel_with_reason("done");
+        let err = th.join().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        assert!(watcher.check().is_err());
+        // Already cancelled, it does not block.
+        watcher.wait();
+    }
+}
@@ -0,0 +1,104 @@
+use crate::CancellationToken;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+// How often a paused operation looks at the cancellation token.
+const PAUSE_POLL: Duration = Duration::from_millis(10);
+
+/// This type signals that operations should be suspended for a while.
+///
+/// Unlike a [`CancellationToken`], a pause can be undone: operations that see
+/// a paused token wait until it is resumed, see
+/// [`CancellationToken::check_or_pause`].
+///
+/// All clones of the same token share its state.
+#[derive(Clone, Default, Debug)]
+pub struct PauseToken {
+    inner: Arc<PauseInner>,
+}
+
+#[derive(Default, Debug)]
+struct PauseInner {
+    paused: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl PauseToken {
+    /// Creates a new `PauseToken`, in a non-paused state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Pauses the operations that use this token.
+    pub fn pause(&self) {
+        *self.inner.paused.lock().unwrap() = true;
+    }
+    /// Resumes the operations that use this token.
+    pub fn resume(&self) {
+        *self.inner.paused.lock().unwrap() = false;
+        self.inner.cond.notify_all();
+    }
+    /// Checks whether this token is paused.
+    pub fn is_paused(&self) -> bool {
+        *self.inner.paused.lock().unwrap()
+    }
+}
+
+impl CancellationToken {
+    /// Checks this token, waiting while `pause` is paused.
+    ///
+    /// It returns `Ok(())` when neither this token is cancelled nor `pause`
+    /// is paused. While paused it blocks, but if this token is cancelled in the
+    /// meantime it returns the cancellation error, so a paused operation can
+    /// still be aborted. A cancellation is noticed within a few milliseconds.
+    pub fn check_or_pause(&self, pause: &PauseToken) -> std::io::Result<()> {
+        let mut paused = pause.inner.paused.lock().unwrap();
+        loop {
+            self.check()?;
+            if !*paused {
+                return Ok(());
+            }
+            paused = pause.inner.cond.wait_timeout(paused, PAUSE_POLL).unwrap().0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_check_or_pause() {
+        let ct = CancellationToken::new();
+        let pause = PauseToken::new();
+        ct.check_or_pause(&pause).unwrap();
+
+        pause.pause();
+        assert!(pause.is_paused());
+        let th = std::thread::spawn({
+            let pause = pause.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(20));
+                pause.resume();
+            }
+        });
+        let start = Instant::now();
+        ct.check_or_pause(&pause).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        th.join().unwrap();
+
+        // Paused, and then cancelled.
+        pause.pause();
+        let th = std::thread::spawn({
+            let ct = ct.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(20));
+                ct.cancel();
+            }
+        });
+        let err = ct.check_or_pause(&pause).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        assert!(pause.is_paused());
+        th.join().unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+use crate::Cancellable;
+use positioned_io::{ReadAt, WriteAt};
+
+impl<T: ReadAt> ReadAt for Cancellable<T> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.check()?;
+        self.inner.read_at(pos, buf)
+    }
+}
+
+impl<T: WriteAt> WriteAt for Cancellable<T> {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> std::io::Result<usize> {
+        self.check()?;
+        self.inner.write_at(pos, buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.check()?;
+        WriteAt::flush(&mut self.inner)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::CancellationToken;
+    use std::io;
+
+    #[test]
+    fn test_read_at() {
+        let ct = CancellationToken::new();
+        let r = Cancellable::new(b"0123456789".to_vec(), ct.clone());
+        let mut data = [0; 3];
+        r.read_exact_at(4, &mut data).unwrap();
+        assert_eq!(&data, b"456");
+        assert_eq!(r.read_at(8, &mut data).unwrap(), 2);
+
+        ct.cancel();
+        let err = r.read_at(0, &mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_concurrent_read_at() {
+        let ct = CancellationToken::new();
+        let data: Vec<u8> = (0..=255).collect();
+        let r = Cancellable::new(data, ct.clone());
+        let reads = std::sync::atomic::AtomicU64::new(0);
+        std::thread::scope(|s| {
+            let threads: Vec<_> = (0..4u64)
+                .map(|i| {
+                    let (r, reads) = (&r, &reads);
+                    s.spawn(move || {
+                        let mut buf = [0; 4];
+                        let mut pos = i * 16;
+                        loop {
+                            match r.read_exact_at(pos, &mut buf) {
+                                Ok(()) => assert_eq!(buf[0], pos as u8),
+                                Err(e) => return e,
+                            }
+                            reads.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            pos = (pos + 7) % 252;
+                        }
+                    })
+                })
+                .collect();
+            while reads.load(std::sync::atomic::Ordering::Relaxed) < 1000 {
+                std::thread::yield_now();
+            }
+            ct.cancel();
+            for th in threads {
+                assert_eq!(th.join().unwrap().kind(), io::ErrorKind::BrokenPipe);
+            }
+        });
+    }
+
+    #[test]
+    fn test_write_at() {
+        let ct = CancellationToken::new();
+        let mut w = Cancellable::new(Vec::new(), ct.clone());
+        w.write_all_at(2, b"ab").unwrap();
+        WriteAt::flush(&mut w).unwrap();
+        assert_eq!(w.get_ref(), b"\0\0ab");
+
+        ct.cancel();
+        let err = w.write_at(0, b"x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        let err = WriteAt::flush(&mut w).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(w.get_ref(), b"\0\0ab");
+    }
+}
@@ -0,0 +1,162 @@
+use crate::CancellationToken;
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+/// A set of tokens that can be cancelled all at once.
+///
+/// A manager of many in flight operations can register the token of each
+/// one when it starts, deregister it when it finishes, and call
+/// [`CancellationRegistry::cancel_all`] to stop everything that is currently
+/// running.
+///
+/// Tokens are stored by identity, so registering a clone of an already
+/// registered token does nothing.
+#[derive(Default, Debug)]
+pub struct CancellationRegistry {
+    tokens: Mutex<HashSet<CancellationToken>>,
+}
+
+impl CancellationRegistry {
+    /// Creates a new empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds a token to the registry.
+    ///
+    /// Returns `false` if it was already registered.
+    pub fn register(&self, token: CancellationToken) -> bool {
+        self.tokens.lock().unwrap().insert(token)
+    }
+    /// Removes a token from the registry.
+    ///
+    /// Returns `false` if it was not registered.
+    pub fn deregister(&self, token: &CancellationToken) -> bool {
+        self.tokens.lock().unwrap().remove(token)
+    }
+    /// Cancels all the tokens currently registered.
+    ///
+    /// The tokens are not removed from the registry. The registry is not
+    /// locked while they are cancelled, so their `on_cancel` callbacks may
+    /// register and deregister tokens.
+    pub fn cancel_all(&self) {
+        let tokens: Vec<_> = self.tokens.lock().unwrap().iter().cloned().collect();
+        for token in tokens {
+            token.cancel();
+        }
+    }
+    /// Counts the registered tokens that are not cancelled.
+    ///
+    /// This is how many of the registered operations are still running, if
+    /// they deregister their token when they finish.
+    pub fn active_count(&self) -> usize {
+        self.tokens
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|t| !t.inner.cancelled.load(Ordering::Relaxed))
+            .count()
+    }
+    /// Counts the registered tokens that are cancelled.
+    pub fn cancelled_count(&self) -> usize {
+        self.tokens
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|t| t.inner.cancelled.load(Ordering::Relaxed))
+            .count()
+    }
+}
+
+/// Registers many tokens at once, as [`CancellationRegistry::register`].
+impl Extend<CancellationToken> for CancellationRegistry {
+    fn extend<I: IntoIterator<Item = CancellationToken>>(&mut self, iter: I) {
+        self.tokens.get_mut().unwrap().extend(iter);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cancel_all() {
+        let registry = CancellationRegistry::new();
+        let tokens: Vec<_> = (0..4).map(|_| CancellationToken::new()).collect();
+        for ct in &tokens {
+            assert!(registry.register(ct.clone()));
+        }
+        assert!(!registry.register(tokens[0].clone()));
+        assert!(registry.deregister(&tokens[1]));
+        assert!(!registry.deregister(&tokens[1]));
+
+        registry.cancel_all();
+        assert!(tokens[0].check().is_err());
+        assert!(tokens[1].check().is_ok());
+        assert!(tokens[2].check().is_err());
+        assert!(tokens[3].check().is_err());
+    }
+
+    #[test]
+    fn test_counts() {
+        let registry = CancellationRegistry::new();
+        let tokens: Vec<_> = (0..5).map(|_| CancellationToken::new()).collect();
+        for ct in &tokens {
+            registry.register(ct.clone());
+        }
+        assert_eq!(
+            (registry.active_count(), registry.cancelled_count()),
+            (5, 0)
+        );
+        tokens[0].cancel();
+        tokens[3].cancel();
+        assert_eq!(
+            (registry.active_count(), registry.cancelled_count()),
+            (3, 2)
+        );
+        registry.deregister(&tokens[0]);
+        registry.deregister(&tokens[1]);
+        assert_eq!(
+            (registry.active_count(), registry.cancelled_count()),
+            (2, 1)
+        );
+        registry.cancel_all();
+        assert_eq!(
+            (registry.active_count(), registry.cancelled_count()),
+            (0, 3)
+        );
+    }
+
+    #[test]
+    fn test_cancel_all_callback() {
+        let registry = std::sync::Arc::new(CancellationRegistry::new());
+        let ct = CancellationToken::new();
+        let late = CancellationToken::new();
+        registry.register(ct.clone());
+        // The callback uses the registry while it is cancelling.
+        ct.on_cancel({
+            let registry = registry.clone();
+            let ct = ct.clone();
+            let late = late.clone();
+            move || {
+                registry.deregister(&ct);
+                registry.register(late);
+            }
+        });
+        registry.cancel_all();
+        assert!(ct.check().is_err());
+        assert!(late.check().is_ok());
+        assert_eq!(registry.active_count(), 1);
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut registry = CancellationRegistry::new();
+        let tokens: Vec<_> = (0..3).map(|_| CancellationToken::new()).collect();
+        registry.extend(tokens.iter().cloned());
+        registry.extend([tokens[0].clone()]);
+        assert_eq!(registry.active_count(), 3);
+        registry.cancel_all();
+        assert!(tokens.iter().all(|ct| ct.check().is_err()));
+    }
+}
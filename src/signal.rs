@@ -0,0 +1,52 @@
+use crate::{CancelCause, CancellationToken};
+use signal_hook::iterator::{Handle, Signals};
+use std::sync::Arc;
+
+impl CancellationToken {
+    /// Creates a new `CancellationToken` that is cancelled when the signal
+    /// `sig` is delivered to the process.
+    ///
+    /// This is typically used with `SIGTERM` or `SIGINT` to trigger a graceful
+    /// shutdown. More signals can be added with
+    /// [`CancellationToken::add_signal`].
+    ///
+    /// While registered, the signal no longer has its default action, for
+    /// example `SIGTERM` will not kill the process anymore.
+    pub fn cancel_on_signal(sig: i32) -> std::io::Result<CancellationToken> {
+        let token = CancellationToken::new();
+        token.add_signal(sig)?;
+        Ok(token)
+    }
+    /// Cancels this token when the signal `sig` is delivered to the process.
+    ///
+    /// A background thread waits for the signal. When the token is cancelled,
+    /// by the signal or otherwise, or when it is dropped, the thread finishes
+    /// and the signal is unregistered. Its default action is not restored, so
+    /// repeated signals are still ignored.
+    pub fn add_signal(&self, sig: i32) -> std::io::Result<()> {
+        let mut signals = Signals::new([sig])?;
+        let handle = CloseOnDrop(signals.handle());
+        let inner = Arc::downgrade(&self.inner);
+        std::thread::spawn(move || {
+            // It ends when the handle is closed.
+            for sig in signals.forever() {
+                if let Some(inner) = inner.upgrade() {
+                    inner.cancel(CancelCause::Signal(sig), None);
+                }
+            }
+        });
+        // The callback is dropped, closing the handle, either after it runs
+        // or together with the token.
+        self.on_cancel(move || drop(handle));
+        Ok(())
+    }
+}
+
+// Closes the signal iterator when dropped.
+struct CloseOnDrop(Handle);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
@@ -0,0 +1,76 @@
+use crate::CancellationToken;
+use futures_core::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A stream that yields a single `()` when a token is cancelled, and then ends.
+///
+/// It is created with [`CancellationToken::as_future_stream`].
+#[derive(Debug)]
+pub struct CancelledStream {
+    token: CancellationToken,
+    done: bool,
+}
+
+impl CancellationToken {
+    /// Gets a `Stream` that yields a single item when this token is cancelled.
+    ///
+    /// Unlike [`CancellationToken::cancelled`] this is a `Stream`, so it can
+    /// be merged with other streams of events. After the item the stream ends.
+    pub fn as_future_stream(&self) -> CancelledStream {
+        CancelledStream {
+            token: self.clone(),
+            done: false,
+        }
+    }
+}
+
+impl Stream for CancelledStream {
+    type Item = ();
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<()>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        match self.token.poll_cancelled(cx) {
+            Poll::Ready(()) => {
+                self.done = true;
+                Poll::Ready(Some(()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::stream::{self, StreamExt};
+
+    #[tokio::test]
+    async fn test_as_future_stream() {
+        let ct = CancellationToken::new();
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let data = rx.map(Some);
+        let cancelled = ct.as_future_stream().map(|()| None);
+        let mut merged =
+            stream::select(data, cancelled).take_while(|x| std::future::ready(x.is_some()));
+
+        tx.unbounded_send(1).unwrap();
+        tx.unbounded_send(2).unwrap();
+        assert_eq!(merged.next().await, Some(Some(1)));
+        assert_eq!(merged.next().await, Some(Some(2)));
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            ct.cancel();
+        });
+        assert_eq!(merged.next().await, None);
+        task.await.unwrap();
+        drop(tx);
+
+        let ct = CancellationToken::new();
+        ct.cancel();
+        let items: Vec<()> = ct.as_future_stream().collect().await;
+        assert_eq!(items, [()]);
+    }
+}
@@ -0,0 +1,126 @@
+// Spans emitted for every operation with the `tracing` feature.
+//
+// Without the feature these functions do nothing, so the callers do not need
+// to care about it.
+
+#[cfg(feature = "tracing")]
+use crate::{classify, Outcome};
+
+// Enters the span of an operation, it is exited when the returned value is dropped.
+#[cfg(feature = "tracing")]
+pub(crate) fn op_span(op: &'static str) -> tracing::span::EnteredSpan {
+    tracing::trace_span!(
+        "cancellable",
+        op,
+        bytes = tracing::field::Empty,
+        cancelled = tracing::field::Empty
+    )
+    .entered()
+}
+
+// Stands for the entered span when there is no `tracing`.
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct NoSpan;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn op_span(_op: &'static str) -> NoSpan {
+    NoSpan
+}
+
+// Records the result of the operation in the current span.
+#[cfg(feature = "tracing")]
+pub(crate) fn record_result(res: &std::io::Result<usize>) {
+    let span = tracing::Span::current();
+    match res {
+        Ok(n) => {
+            span.record("bytes", n);
+            span.record("cancelled", false);
+        }
+        Err(e) => {
+            span.record("cancelled", matches!(classify(e), Outcome::Cancelled(_)));
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn record_result(_res: &std::io::Result<usize>) {}
+
+#[cfg(all(test, feature = "tracing"))]
+mod test {
+    use crate::{Cancellable, CancellationToken};
+    use std::fmt::Debug;
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+    use tracing_core::span::Current;
+
+    // Collects the fields of every span, in the order they are recorded.
+    #[derive(Default)]
+    struct Collector {
+        spans: Arc<Mutex<Vec<Vec<String>>>>,
+        metadata: Mutex<Vec<&'static Metadata<'static>>>,
+        entered: Mutex<Vec<u64>>,
+    }
+
+    struct Fields<'a>(&'a mut Vec<String>);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut spans = self.spans.lock().unwrap();
+            let mut fields = Vec::new();
+            span.record(&mut Fields(&mut fields));
+            spans.push(fields);
+            self.metadata.lock().unwrap().push(span.metadata());
+            Id::from_u64(spans.len() as u64)
+        }
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut Fields(&mut spans[span.into_u64() as usize - 1]));
+        }
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, _: &Event<'_>) {}
+        fn enter(&self, span: &Id) {
+            self.entered.lock().unwrap().push(span.into_u64());
+        }
+        fn exit(&self, _: &Id) {
+            self.entered.lock().unwrap().pop();
+        }
+        fn current_span(&self) -> Current {
+            match self.entered.lock().unwrap().last() {
+                Some(&id) => Current::new(
+                    Id::from_u64(id),
+                    self.metadata.lock().unwrap()[id as usize - 1],
+                ),
+                None => Current::none(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_spans() {
+        let collector = Collector::default();
+        let spans = collector.spans.clone();
+        tracing::subscriber::with_default(collector, || {
+            let ct = CancellationToken::new();
+            let mut r = Cancellable::new(&b"abc"[..], ct.clone());
+            assert_eq!(r.read(&mut [0; 2]).unwrap(), 2);
+            ct.cancel();
+            assert!(r.read(&mut [0; 2]).is_err());
+        });
+        let spans = spans.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0], ["op=\"read\"", "bytes=2", "cancelled=false"]);
+        assert_eq!(spans[1], ["op=\"read\"", "cancelled=true"]);
+    }
+}
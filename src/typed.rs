@@ -0,0 +1,248 @@
+use crate::{Cancellable, CancellationToken};
+use std::io::{self, BufRead, Read, Seek, Write};
+use std::ops::{Deref, DerefMut};
+
+macro_rules! typed_cancellable {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        pub struct $name<T>(Cancellable<T>);
+
+        impl<T> $name<T> {
+            /// Wraps a value, see [`Cancellable::new`].
+            pub fn new(inner: T, token: CancellationToken) -> Self {
+                $name(Cancellable::new(inner, token))
+            }
+            /// Unwraps the inner `Cancellable`.
+            pub fn into_cancellable(self) -> Cancellable<T> {
+                self.0
+            }
+        }
+
+        impl<T> Deref for $name<T> {
+            type Target = Cancellable<T>;
+
+            fn deref(&self) -> &Cancellable<T> {
+                &self.0
+            }
+        }
+
+        impl<T> DerefMut for $name<T> {
+            fn deref_mut(&mut self) -> &mut Cancellable<T> {
+                &mut self.0
+            }
+        }
+
+        impl<T> From<Cancellable<T>> for $name<T> {
+            fn from(c: Cancellable<T>) -> Self {
+                $name(c)
+            }
+        }
+
+        impl<T> From<$name<T>> for Cancellable<T> {
+            fn from(c: $name<T>) -> Self {
+                c.0
+            }
+        }
+    };
+}
+
+typed_cancellable! {
+    /// A [`Cancellable`] used as a reader.
+    ///
+    /// It derefs to `Cancellable<R>`, but makes function signatures more
+    /// explicit, and adds some reader specific helpers.
+    CancellableReader
+}
+
+typed_cancellable! {
+    /// A [`Cancellable`] used as a writer.
+    ///
+    /// It derefs to `Cancellable<W>`, but makes function signatures more
+    /// explicit, and adds some writer specific helpers.
+    CancellableWriter
+}
+
+typed_cancellable! {
+    /// A [`Cancellable`] used as a seekable stream.
+    ///
+    /// It derefs to `Cancellable<S>`, but makes function signatures more
+    /// explicit.
+    CancellableSeeker
+}
+
+impl<R: Read> CancellableReader<R> {
+    /// Reads all the remaining data into a new `Vec`.
+    pub fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.0.read_to_end(&mut data)?;
+        Ok(data)
+    }
+    /// Reads and discards `n` bytes.
+    ///
+    /// It returns the number of bytes actually skipped, that can be less than
+    /// `n` if the stream ends before.
+    pub fn skip(&mut self, n: u64) -> io::Result<u64> {
+        io::copy(&mut (&mut self.0).take(n), &mut io::sink())
+    }
+}
+
+// All the methods that `Cancellable` overrides are forwarded, or its own
+// loops and checks would be bypassed by the default implementations.
+impl<R: Read> Read for CancellableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        self.0.read_vectored(bufs)
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.0.read_to_end(buf)
+    }
+
+    fn read_to_string(&mut self, buf: &mut String) -> io::Result<usize> {
+        self.0.read_to_string(buf)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.0.read_exact(buf)
+    }
+}
+
+impl<R: BufRead> BufRead for CancellableReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.0.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.0.consume(amt)
+    }
+}
+
+impl<W: Write> CancellableWriter<W> {
+    /// Flushes the writer and unwraps the inner value.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.0.flush()?;
+        Ok(self.0.into_inner())
+    }
+}
+
+impl<W: Write> Write for CancellableWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.0.write_vectored(bufs)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf)
+    }
+
+    fn write_fmt(&mut self, fmt: std::fmt::Arguments<'_>) -> io::Result<()> {
+        self.0.write_fmt(fmt)
+    }
+}
+
+impl<S: Seek> Seek for CancellableSeeker<S> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.0.rewind()
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        self.0.stream_position()
+    }
+
+    fn seek_relative(&mut self, offset: i64) -> io::Result<()> {
+        self.0.seek_relative(offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reader() {
+        let ct = CancellationToken::new();
+        let mut r = CancellableReader::new(&b"0123456789"[..], ct.clone());
+        assert_eq!(r.skip(4).unwrap(), 4);
+        assert_eq!(r.read_all().unwrap(), b"456789");
+        assert_eq!(r.skip(4).unwrap(), 0);
+        assert_eq!(r.token(), &ct);
+
+        ct.cancel();
+        let err = r.read_all().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        let c: Cancellable<_> = r.into();
+        assert!(c.get_ref().is_empty());
+    }
+
+    #[test]
+    fn test_forwarded() {
+        // `read_exact` is a single operation of `Cancellable`, done in chunks.
+        let ct = CancellationToken::new();
+        let mut r = CancellableReader::from(
+            Cancellable::new(&b"0123456789"[..], ct)
+                .chunked(2)
+                .op_limit(1),
+        );
+        let mut buf = [0; 4];
+        r.read_exact(&mut buf).unwrap();
+        assert!(r.fill_buf().is_err());
+
+        let ct = CancellationToken::new();
+        let mut w = CancellableWriter::from(Cancellable::new(Vec::new(), ct).split_vectored());
+        let bufs = [io::IoSlice::new(b"ab"), io::IoSlice::new(b"cd")];
+        assert_eq!(w.write_vectored(&bufs).unwrap(), 4);
+        let mut w = CancellableWriter::from(
+            Cancellable::new(Vec::new(), CancellationToken::new()).max_write(1),
+        );
+        assert_eq!(w.write_vectored(&bufs).unwrap(), 2);
+        assert_eq!(w.finish().unwrap(), b"ab");
+
+        let ct = CancellationToken::new();
+        let mut s = CancellableSeeker::new(io::Cursor::new(vec![0; 10]), ct.clone());
+        s.seek_relative(3).unwrap();
+        assert_eq!(s.stream_position().unwrap(), 3);
+        ct.cancel();
+        assert!(s.rewind().is_err());
+        assert_eq!(s.get_ref().position(), 3);
+    }
+
+    #[test]
+    fn test_writer() {
+        let ct = CancellationToken::new();
+        let mut w = CancellableWriter::from(Cancellable::new(Vec::new(), ct.clone()));
+        w.write_all(b"abc").unwrap();
+        assert_eq!(w.finish().unwrap(), b"abc");
+
+        let mut w = CancellableWriter::new(Vec::new(), ct.clone());
+        w.write_all(b"abc").unwrap();
+        ct.cancel();
+        let err = w.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_seeker() {
+        let ct = CancellationToken::new();
+        let mut s = CancellableSeeker::new(io::Cursor::new(vec![0; 10]), ct.clone());
+        assert_eq!(s.seek(io::SeekFrom::End(-2)).unwrap(), 8);
+        assert_eq!(s.length().unwrap(), 10);
+        ct.cancel();
+        let err = s.seek(io::SeekFrom::Start(0)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(s.into_cancellable().into_inner().position(), 8);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::CancellationToken;
+use crossbeam_channel::{bounded, select, Receiver};
+use std::sync::Arc;
+
+/// A newtype around a `crossbeam_channel::Receiver` that makes it cancellable.
+///
+/// A blocked [`CancellableCrossbeamReceiver::recv_cancellable`] is woken up
+/// as soon as the token is cancelled, without polling.
+#[derive(Debug)]
+pub struct CancellableCrossbeamReceiver<T> {
+    rx: Receiver<T>,
+    // Disconnected when the token is cancelled.
+    cancelled: Receiver<()>,
+    token: CancellationToken,
+}
+
+impl CancellationToken {
+    // Gets a receiver that is disconnected when this token is cancelled. All
+    // the receivers share one channel, so it registers a single callback in
+    // the token, and only again after it is cancelled and reset.
+    fn crossbeam_signal(&self) -> Receiver<()> {
+        let mut signal = self.inner.crossbeam.lock().unwrap();
+        if let Some((_, rx)) = &*signal {
+            return rx.clone();
+        }
+        let (tx, rx) = bounded::<()>(0);
+        *signal = Some((tx, rx.clone()));
+        drop(signal);
+        // Weak, so that the token does not keep itself alive.
+        let inner = Arc::downgrade(&self.inner);
+        self.on_cancel(move || {
+            if let Some(inner) = inner.upgrade() {
+                inner.crossbeam.lock().unwrap().take();
+            }
+        });
+        rx
+    }
+}
+
+impl<T> CancellableCrossbeamReceiver<T> {
+    /// Wraps a receiver as `CancellableCrossbeamReceiver`.
+    ///
+    /// All the receivers of a token share a single callback in it, see
+    /// [`CancellationToken::on_cancel`], so creating many of them does not
+    /// pile up callbacks in a long-lived token.
+    pub fn new(rx: Receiver<T>, token: CancellationToken) -> Self {
+        let cancelled = token.crossbeam_signal();
+        CancellableCrossbeamReceiver {
+            rx,
+            cancelled,
+            token,
+        }
+    }
+    /// Receives the next message, blocking until there is one or the token is cancelled.
+    ///
+    /// It returns `Ok(None)` if the channel is disconnected, and the
+    /// cancellation error if the token is cancelled, even if there are
+    /// messages waiting.
+    pub fn recv_cancellable(&self) -> std::io::Result<Option<T>> {
+        self.token.check()?;
+        select! {
+            recv(self.rx) -> msg => match msg {
+                Ok(msg) => Ok(Some(msg)),
+                Err(_) => Ok(None),
+            },
+            recv(self.cancelled) -> _ => Err(self.token.error()),
+        }
+    }
+    /// Gets the inner token.
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+    /// Unwraps the inner receiver.
+    pub fn into_inner(self) -> Receiver<T> {
+        self.rx
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crossbeam_channel::unbounded;
+    use std::time::Duration;
+
+    #[test]
+    fn test_recv_cancellable() {
+        let ct = CancellationToken::new();
+        let (tx, rx) = unbounded();
+        let rx = CancellableCrossbeamReceiver::new(rx, ct.clone());
+        tx.send(1).unwrap();
+        assert_eq!(rx.recv_cancellable().unwrap(), Some(1));
+
+        let th = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            ct.cancel();
+        });
+        let err = rx.recv_cancellable().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        th.join().unwrap();
+        tx.send(2).unwrap();
+        assert!(rx.recv_cancellable().is_err());
+
+        let (tx, rx) = unbounded::<i32>();
+        let rx = CancellableCrossbeamReceiver::new(rx, CancellationToken::new());
+        drop(tx);
+        assert_eq!(rx.recv_cancellable().unwrap(), None);
+    }
+
+    #[test]
+    fn test_shared_callback() {
+        let ct = CancellationToken::new();
+        for _ in 0..100 {
+            let (_tx, rx) = unbounded::<i32>();
+            drop(CancellableCrossbeamReceiver::new(rx, ct.clone()));
+        }
+        assert_eq!(ct.inner.callbacks.lock().unwrap().0.len(), 1);
+        let (_tx, rx) = unbounded::<i32>();
+        let rx = CancellableCrossbeamReceiver::new(rx, ct.clone());
+        ct.cancel();
+        assert!(rx.recv_cancellable().is_err());
+
+        // A new channel after a reset.
+        assert!(ct.reset_if(true));
+        let (tx, rx) = unbounded();
+        let rx = CancellableCrossbeamReceiver::new(rx, ct.clone());
+        tx.send(1).unwrap();
+        assert_eq!(rx.recv_cancellable().unwrap(), Some(1));
+        ct.cancel();
+        assert!(rx.recv_cancellable().is_err());
+    }
+}
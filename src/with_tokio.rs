@@ -0,0 +1,408 @@
+use crate::{Cancellable, CancellationToken, WakerSlot};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, ReadBuf};
+use tokio::sync::{mpsc, watch};
+
+// Wakes up by sending `true` to a watch channel.
+struct WatchWaker(watch::Sender<bool>);
+
+impl Wake for WatchWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.send_replace(true);
+    }
+}
+
+impl CancellationToken {
+    /// Gets a `tokio::sync::watch::Receiver` that reflects the state of this token.
+    ///
+    /// The value is `true` if the token is cancelled, and it changes to
+    /// `true` when it is, so it can be awaited with `changed()`, or
+    /// `wait_for(|c| *c)`.
+    ///
+    /// All the receivers of a token share the same channel, so calling this
+    /// many times does not register more wakers in the token.
+    pub fn watch(&self) -> watch::Receiver<bool> {
+        let (tx, waker) = self.inner.watch.get_or_init(|| {
+            let (tx, _) = watch::channel(false);
+            let waker = Waker::from(Arc::new(WatchWaker(tx.clone())));
+            (tx, waker)
+        });
+        // The waker is the same every time, so it is only registered once,
+        // until the token is cancelled, or again after it is reset.
+        let cancelled = self
+            .poll_cancelled(&mut Context::from_waker(waker))
+            .is_ready();
+        tx.send_if_modified(|c| std::mem::replace(c, cancelled) != cancelled);
+        tx.subscribe()
+    }
+    /// Registers an async task to be spawned when this token is cancelled.
+    ///
+    /// When the token is cancelled, `f` is called and the future it returns
+    /// is spawned in the tokio runtime that was current when this function was
+    /// called. It is useful for async cleanup, such as closing connections.
+    /// It runs only once, even if the token is cancelled concurrently by many
+    /// threads. If the token is already cancelled, the task is spawned now.
+    ///
+    /// # Panics
+    ///
+    /// If it is not called from within a tokio runtime.
+    pub fn on_cancel_async<F, Fut>(&self, f: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let handle = tokio::runtime::Handle::current();
+        self.on_cancel(move || {
+            handle.spawn(f());
+        });
+    }
+    /// Blocks the current thread until this token is cancelled.
+    ///
+    /// This is the blocking counterpart of [`CancellationToken::cancelled`],
+    /// for code running in `tokio::task::spawn_blocking` or inside
+    /// `tokio::task::block_in_place`. The thread is parked, it does not spin,
+    /// and it is woken up as soon as the token is cancelled.
+    ///
+    /// Do not call it directly from an async task: it would block a worker
+    /// thread of the runtime, await `cancelled()` instead.
+    ///
+    /// See [`CancellationToken::wait`].
+    pub fn block_in_place_until_cancelled(&self) {
+        self.wait()
+    }
+}
+
+/// A newtype around a `tokio::sync::mpsc::Receiver` that makes it cancellable.
+#[derive(Debug)]
+pub struct CancellableReceiver<T> {
+    rx: mpsc::Receiver<T>,
+    token: CancellationToken,
+    waker: WakerSlot,
+}
+
+impl<T> CancellableReceiver<T> {
+    /// Wraps a receiver as `CancellableReceiver`.
+    pub fn new(rx: mpsc::Receiver<T>, token: CancellationToken) -> Self {
+        CancellableReceiver {
+            rx,
+            token,
+            waker: WakerSlot::default(),
+        }
+    }
+    /// Receives the next message, or waits for the token to be cancelled.
+    ///
+    /// It returns `Ok(None)` if the channel is closed, and the cancellation
+    /// error if the token is cancelled, even if there are messages waiting.
+    pub async fn recv_cancellable(&mut self) -> std::io::Result<Option<T>> {
+        std::future::poll_fn(|cx| {
+            // A single waker per receiver, however many times it is polled.
+            if self.waker.poll(&self.token, cx).is_ready() {
+                return Poll::Ready(Err(self.token.error()));
+            }
+            let res = self.rx.poll_recv(cx).map(Ok);
+            if res.is_ready() {
+                self.waker.clear();
+            }
+            res
+        })
+        .await
+    }
+    /// Gets the inner token.
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+    /// Unwraps the inner receiver.
+    pub fn into_inner(self) -> mpsc::Receiver<T> {
+        self.rx
+    }
+}
+
+impl<T> Cancellable<T> {
+    // Checks the token before every poll of an async operation, registering
+    // the waker so that a pending operation is woken up by the cancellation.
+    // The waker is kept in the slot of this wrapper, so polling again only
+    // replaces it, and it is removed when the operation is ready.
+    // An operation is only taken from the limit when the inner poll is ready,
+    // by `poll_done`, so that the pending polls do not use it up.
+    fn poll_check(&mut self, cx: &mut Context<'_>) -> std::io::Result<()> {
+        let _ = self.waker.poll(&self.token, cx);
+        let res = self.check_token().and_then(|()| match &self.ops_left {
+            Some(ops_left) if ops_left.load(Ordering::Relaxed) == 0 => Err(self.op_limit_reached()),
+            _ => Ok(()),
+        });
+        if res.is_err() {
+            self.waker.clear();
+        }
+        res
+    }
+    // Accounts for an async operation that is ready, see `poll_check`. It
+    // does not borrow the whole `Cancellable`, because `poll_fill_buf` cannot
+    // do that.
+    fn poll_done<R>(
+        waker: &mut WakerSlot,
+        last_aborted: &AtomicBool,
+        ops_left: &Option<AtomicU64>,
+        res: Poll<R>,
+    ) -> Poll<R> {
+        if res.is_ready() {
+            waker.clear();
+            last_aborted.store(false, Ordering::Relaxed);
+            if let Some(ops_left) = ops_left {
+                // There was one left in `poll_check`, and it is not shared.
+                ops_left.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        res
+    }
+}
+
+impl<T: AsyncBufRead + Unpin> Cancellable<T> {
+    /// Reads a line into `buf`, or waits for the token to be cancelled.
+    ///
+    /// The token is checked every time the inner buffer is filled, so a long
+    /// line from a slow source can still be interrupted. As with
+    /// `AsyncBufReadExt::read_line`, on error the part of the line read so
+    /// far may be left in `buf`.
+    pub async fn read_line_cancellable(&mut self, buf: &mut String) -> std::io::Result<usize> {
+        self.read_line(buf).await
+    }
+}
+
+/// The token is checked before every read, and a pending read fails with the
+/// cancellation error as soon as the token is cancelled. A read counts as a
+/// single operation for [`Cancellable::op_limit`], however many times it is
+/// polled.
+impl<T: AsyncRead + Unpin> AsyncRead for Cancellable<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if let Err(e) = this.poll_check(cx) {
+            return Poll::Ready(Err(e));
+        }
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        Self::poll_done(&mut this.waker, &this.last_aborted, &this.ops_left, res)
+    }
+}
+
+/// The token is checked before every fill of the buffer, as in `AsyncRead`.
+impl<T: AsyncBufRead + Unpin> AsyncBufRead for Cancellable<T> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if let Err(e) = this.poll_check(cx) {
+            return Poll::Ready(Err(e));
+        }
+        let res = Pin::new(&mut this.inner).poll_fill_buf(cx);
+        Self::poll_done(&mut this.waker, &this.last_aborted, &this.ops_left, res)
+    }
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.get_mut().inner).consume(amt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_watch() {
+        let ct = CancellationToken::new();
+        let mut rx = ct.watch();
+        assert!(!*rx.borrow());
+
+        let task = tokio::spawn({
+            let ct = ct.clone();
+            async move {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                ct.cancel();
+            }
+        });
+        rx.changed().await.unwrap();
+        assert!(*rx.borrow());
+        task.await.unwrap();
+
+        let rx = ct.watch();
+        assert!(*rx.borrow());
+    }
+
+    #[tokio::test]
+    async fn test_op_limit_pending() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let mut r = Cancellable::new(rx, CancellationToken::new()).op_limit(1);
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            tx.write_all(b"abc").await.unwrap();
+            tx
+        });
+        // Pending until the data is written, and it uses the limit only once.
+        let mut buf = [0; 8];
+        assert_eq!(r.read(&mut buf).await.unwrap(), 3);
+        assert!(!r.last_op_aborted());
+        let _tx = task.await.unwrap();
+        let err = r.read(&mut buf).await.unwrap_err();
+        assert_eq!(
+            crate::classify(&err),
+            crate::Outcome::Cancelled(Some("operation limit reached".to_string()))
+        );
+        assert!(r.last_op_aborted());
+    }
+
+    #[test]
+    fn test_poll_read_one_waker() {
+        use tokio::io::AsyncWrite;
+
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let ct = CancellationToken::new();
+        let mut r = Cancellable::new(rx, ct.clone());
+        let mut buf = [0; 8];
+        let mut buf = ReadBuf::new(&mut buf);
+        let mut cx = Context::from_waker(Waker::noop());
+        let wakers = || ct.inner.wakers.lock().unwrap().list.len();
+        for _ in 0..10 {
+            assert!(Pin::new(&mut r).poll_read(&mut cx, &mut buf).is_pending());
+        }
+        assert_eq!(wakers(), 1);
+        // Removed when the read is done, and when a pending read is dropped.
+        assert!(Pin::new(&mut tx).poll_write(&mut cx, b"abc").is_ready());
+        assert!(Pin::new(&mut r).poll_read(&mut cx, &mut buf).is_ready());
+        assert_eq!(wakers(), 0);
+        assert!(Pin::new(&mut r).poll_read(&mut cx, &mut buf).is_pending());
+        assert_eq!(wakers(), 1);
+        drop(r);
+        assert_eq!(wakers(), 0);
+    }
+
+    #[test]
+    fn test_watch_shared() {
+        let ct = CancellationToken::new();
+        let receivers: Vec<_> = (0..100).map(|_| ct.watch()).collect();
+        assert_eq!(ct.inner.wakers.lock().unwrap().list.len(), 1);
+        ct.cancel();
+        assert!(receivers.iter().all(|rx| *rx.borrow()));
+
+        assert!(ct.reset_if(true));
+        let rx = ct.watch();
+        assert!(!*rx.borrow());
+        ct.cancel();
+        assert!(*rx.borrow());
+    }
+
+    #[tokio::test]
+    async fn test_block_in_place_until_cancelled() {
+        let ct = CancellationToken::new();
+        let task = tokio::task::spawn_blocking({
+            let ct = ct.clone();
+            move || {
+                ct.block_in_place_until_cancelled();
+                ct.check().is_err()
+            }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert!(!task.is_finished());
+        ct.cancel();
+        assert!(task.await.unwrap());
+        // Already cancelled, it returns immediately.
+        ct.block_in_place_until_cancelled();
+    }
+
+    #[tokio::test]
+    async fn test_on_cancel_async() {
+        let ct = CancellationToken::new();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        ct.on_cancel_async({
+            let tx = tx.clone();
+            move || async move {
+                tokio::task::yield_now().await;
+                tx.send("cleanup").unwrap();
+            }
+        });
+        tokio::task::yield_now().await;
+        assert!(rx.try_recv().is_err());
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let ct = ct.clone();
+                std::thread::spawn(move || ct.cancel())
+            })
+            .collect();
+        for th in threads {
+            th.join().unwrap();
+        }
+        assert_eq!(rx.recv().await, Some("cleanup"));
+        drop(tx);
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_recv_cancellable() {
+        let ct = CancellationToken::new();
+        let (tx, rx) = mpsc::channel(4);
+        let mut rx = CancellableReceiver::new(rx, ct.clone());
+        tx.send(1).await.unwrap();
+        assert_eq!(rx.recv_cancellable().await.unwrap(), Some(1));
+
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            ct.cancel();
+        });
+        let err = rx.recv_cancellable().await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        task.await.unwrap();
+
+        let (tx, rx) = mpsc::channel::<i32>(4);
+        let mut rx = CancellableReceiver::new(rx, CancellationToken::new());
+        drop(tx);
+        assert_eq!(rx.recv_cancellable().await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_recv_cancellable_one_waker() {
+        let ct = CancellationToken::new();
+        let (tx, rx) = mpsc::channel(4);
+        let mut rx = CancellableReceiver::new(rx, ct.clone());
+        let mut cx = Context::from_waker(Waker::noop());
+        let wakers = || ct.inner.wakers.lock().unwrap().list.len();
+        {
+            let mut recv = std::pin::pin!(rx.recv_cancellable());
+            for _ in 0..10 {
+                assert!(recv.as_mut().poll(&mut cx).is_pending());
+            }
+            assert_eq!(wakers(), 1);
+            tx.try_send(1).unwrap();
+            assert!(matches!(recv.poll(&mut cx), Poll::Ready(Ok(Some(1)))));
+        }
+        assert_eq!(wakers(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_read_line_cancellable() {
+        use tokio::io::AsyncWriteExt;
+
+        let ct = CancellationToken::new();
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let mut r = Cancellable::new(tokio::io::BufReader::new(rx), ct.clone());
+        tx.write_all(b"first line\nsecond").await.unwrap();
+        let mut line = String::new();
+        assert_eq!(r.read_line_cancellable(&mut line).await.unwrap(), 11);
+        assert_eq!(line, "first line\n");
+
+        // The rest of the line never comes.
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            ct.cancel();
+        });
+        line.clear();
+        let err = r.read_line_cancellable(&mut line).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        task.await.unwrap();
+        drop(tx);
+    }
+}
@@ -0,0 +1,132 @@
+use cancel_rw::{Cancellable, CancellationToken};
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+
+const CAPACITY: usize = 64 * 1024;
+
+// A slow link, that blocks in every read until the test lets it go, as a big
+// read from a slow socket. It tells the test when it is inside a read, and
+// counts the reads.
+struct SlowLink {
+    inside: mpsc::Sender<()>,
+    release: mpsc::Receiver<()>,
+    reads: Arc<AtomicUsize>,
+}
+
+impl Read for SlowLink {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        let _ = self.inside.send(());
+        // Once the test drops the sender, this does not block any more.
+        let _ = self.release.recv();
+        let n = buf.len().min(CAPACITY);
+        buf[..n].fill(b'x');
+        Ok(n)
+    }
+}
+
+// The test side of a `SlowLink`.
+struct Link {
+    inside: mpsc::Receiver<()>,
+    release: mpsc::Sender<()>,
+    reads: Arc<AtomicUsize>,
+}
+
+fn slow_link() -> (SlowLink, Link) {
+    let (inside_tx, inside) = mpsc::channel();
+    let (release, release_rx) = mpsc::channel();
+    let reads = Arc::new(AtomicUsize::new(0));
+    let link = SlowLink {
+        inside: inside_tx,
+        release: release_rx,
+        reads: reads.clone(),
+    };
+    (
+        link,
+        Link {
+            inside,
+            release,
+            reads,
+        },
+    )
+}
+
+// Runs `op` in another thread, cancels `ct` while the first read of the link
+// is blocked, and then lets the link go. Returns the result of `op` and the
+// number of reads done by the link.
+fn cancel_inside_read<R: Send + 'static>(
+    ct: &CancellationToken,
+    link: Link,
+    op: impl FnOnce() -> (io::Result<usize>, R) + Send + 'static,
+) -> (io::Result<usize>, R, usize) {
+    let th = std::thread::spawn(op);
+    link.inside.recv().unwrap();
+    ct.cancel();
+    drop(link.release);
+    let (res, r) = th.join().unwrap();
+    (res, r, link.reads.load(Ordering::Relaxed))
+}
+
+#[test]
+fn cancellable_around_bufreader() {
+    // The token cannot be checked in the middle of a fill, the whole buffer
+    // is filled before the cancellation is seen.
+    let ct = CancellationToken::new();
+    let (inner, link) = slow_link();
+    let mut r = Cancellable::new(BufReader::with_capacity(CAPACITY, inner), ct.clone());
+    let (res, line, reads) = cancel_inside_read(&ct, link, move || {
+        let mut line = String::new();
+        (r.read_line(&mut line), line)
+    });
+    assert_eq!(res.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    assert_eq!(line.len(), CAPACITY);
+    assert_eq!(reads, 1);
+}
+
+#[test]
+fn bufreader_around_cancellable() {
+    // Chunked, a fill is split into small inner reads, and the token is
+    // checked at the next one.
+    let ct = CancellationToken::new();
+    let (inner, link) = slow_link();
+    let inner = Cancellable::new(inner, ct.clone()).chunked(1024);
+    let mut r = BufReader::with_capacity(CAPACITY, inner);
+    let (res, line, reads) = cancel_inside_read(&ct, link, move || {
+        let mut line = String::new();
+        (r.read_line(&mut line), line)
+    });
+    assert_eq!(res.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    assert_eq!(line.len(), 1024);
+    assert_eq!(reads, 1);
+
+    let ct = CancellationToken::new();
+    let (inner, link) = slow_link();
+    let inner = Cancellable::new(inner, ct.clone()).chunked(1024);
+    let mut r = BufReader::with_capacity(CAPACITY, inner);
+    let (res, _, reads) =
+        cancel_inside_read(&ct, link, move || (r.read_to_end(&mut Vec::new()), ()));
+    assert_eq!(res.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    assert_eq!(reads, 1);
+}
+
+#[test]
+fn buffered_data_after_cancel() {
+    let mut buf = [0; 16];
+
+    // Wrapping the `BufReader`, every call checks the token.
+    let ct = CancellationToken::new();
+    let mut r = Cancellable::new(BufReader::new(io::repeat(b'x')), ct.clone());
+    r.read_exact(&mut buf).unwrap();
+    assert!(!r.get_ref().buffer().is_empty());
+    ct.cancel();
+    assert!(r.read(&mut buf).is_err());
+
+    // Wrapping the inner value, the buffered data is still returned.
+    let ct = CancellationToken::new();
+    let mut r = BufReader::with_capacity(32, Cancellable::new(io::repeat(b'x'), ct.clone()));
+    r.read_exact(&mut buf).unwrap();
+    ct.cancel();
+    r.read_exact(&mut buf).unwrap();
+    assert!(r.read(&mut buf).is_err());
+}
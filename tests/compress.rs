@@ -0,0 +1,83 @@
+use cancel_rw::{classify, Cancellable, CancellationToken, Outcome};
+use flate2::{read, write, Compression};
+use std::io::{self, Read, Write};
+
+// Data that does not compress well, so the compressor emits output early.
+fn noise(len: usize) -> Vec<u8> {
+    let mut x: u32 = 12345;
+    (0..len)
+        .map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 16) as u8
+        })
+        .collect()
+}
+
+// Collects the output, cancelling the token after `limit` bytes.
+struct CancelAfter {
+    out: Vec<u8>,
+    limit: usize,
+    ct: CancellationToken,
+}
+
+impl Write for CancelAfter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.extend_from_slice(buf);
+        if self.out.len() >= self.limit {
+            self.ct.cancel();
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn gzip_writer_cancelled() {
+    let ct = CancellationToken::new();
+    let sink = CancelAfter {
+        out: Vec::new(),
+        limit: 64 * 1024,
+        ct: ct.clone(),
+    };
+    let mut gz = write::GzEncoder::new(Cancellable::new(sink, ct), Compression::fast());
+    let data = noise(1024 * 1024);
+    let mut consumed = 0;
+    let err = loop {
+        match gz.write(&data[consumed..(consumed + 4096).min(data.len())]) {
+            Ok(0) => panic!("finished before cancelling"),
+            Ok(n) => consumed += n,
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(classify(&err), Outcome::Cancelled(None));
+    assert!(consumed < data.len());
+    let sink = gz.get_ref().get_ref();
+    assert!(sink.out.len() >= 64 * 1024);
+    assert!(sink.out.len() < data.len());
+    // Finishing also fails, the output stream is aborted.
+    assert!(gz.finish().is_err());
+}
+
+#[test]
+fn gzip_reader_cancelled() {
+    // To abort reading the input, wrap the source reader.
+    let ct = CancellationToken::new();
+    let data = noise(1024 * 1024);
+    let src = Cancellable::new(&data[..], ct.clone());
+    let mut gz = read::GzEncoder::new(src, Compression::fast());
+    let mut out = [0; 4096];
+    let mut total = 0;
+    let err = loop {
+        match gz.read(&mut out) {
+            Ok(0) => panic!("finished before cancelling"),
+            Ok(n) => total += n,
+            Err(e) => break e,
+        }
+        if total >= 64 * 1024 {
+            ct.cancel();
+        }
+    };
+    assert_eq!(classify(&err), Outcome::Cancelled(None));
+}
@@ -0,0 +1,18 @@
+// The panic hook is global, so this has its own test binary.
+use cancel_rw::{CancelCause, CancellationToken};
+
+#[test]
+fn panic_hook() {
+    let ct = CancellationToken::new();
+    ct.install_panic_hook();
+    ct.install_panic_hook();
+    let other = CancellationToken::new();
+    other.install_panic_hook();
+    assert!(ct.check().is_ok());
+
+    let res = std::thread::spawn(|| panic!("worker failed")).join();
+    assert!(res.is_err());
+    assert_eq!(ct.cancel_cause(), Some(CancelCause::Panic));
+    assert!(ct.reason().unwrap().contains("worker failed"));
+    assert!(other.check().is_err());
+}
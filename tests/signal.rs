@@ -0,0 +1,79 @@
+#![cfg(all(unix, feature = "signal"))]
+
+use cancel_rw::{CancelCause, CancellationToken};
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
+use signal_hook::low_level::raise;
+use std::time::{Duration, Instant};
+
+fn wait_cancelled(ct: &CancellationToken) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(5) {
+        if ct.check().is_err() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+// Signals are process wide, so everything is in the same test.
+#[test]
+fn cancel_on_signal() {
+    let ct = CancellationToken::cancel_on_signal(SIGTERM).unwrap();
+    assert!(ct.check().is_ok());
+    raise(SIGTERM).unwrap();
+    assert!(wait_cancelled(&ct));
+    assert_eq!(ct.cancel_cause(), Some(CancelCause::Signal(SIGTERM)));
+    // Still handled, so it does not kill the test.
+    raise(SIGTERM).unwrap();
+
+    let ct = CancellationToken::cancel_on_signal(SIGUSR1).unwrap();
+    ct.add_signal(SIGUSR2).unwrap();
+    raise(SIGUSR2).unwrap();
+    assert!(wait_cancelled(&ct));
+    assert_eq!(ct.cancel_cause(), Some(CancelCause::Signal(SIGUSR2)));
+
+    let ct = CancellationToken::cancelled_by()
+        .after(Duration::from_secs(60))
+        .on_ctrl_c()
+        .build()
+        .unwrap();
+    raise(SIGINT).unwrap();
+    assert!(wait_cancelled(&ct));
+    assert_eq!(ct.cancel_cause(), Some(CancelCause::Signal(SIGINT)));
+
+    // The background thread finishes when the token is cancelled or dropped.
+    #[cfg(target_os = "linux")]
+    {
+        let before = threads();
+        let ct = CancellationToken::cancel_on_signal(SIGUSR1).unwrap();
+        assert!(wait_threads(before + 1));
+        ct.cancel();
+        assert!(wait_threads(before));
+
+        let ct = CancellationToken::cancel_on_signal(SIGUSR1).unwrap();
+        assert!(wait_threads(before + 1));
+        drop(ct);
+        assert!(wait_threads(before));
+        // Unregistered, but still ignored.
+        raise(SIGUSR1).unwrap();
+    }
+}
+
+// The number of threads of this process.
+#[cfg(target_os = "linux")]
+fn threads() -> usize {
+    std::fs::read_dir("/proc/self/task").unwrap().count()
+}
+
+#[cfg(target_os = "linux")]
+fn wait_threads(n: usize) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(5) {
+        if threads() == n {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
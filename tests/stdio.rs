@@ -0,0 +1,85 @@
+use cancel_rw::{Cancellable, CancellationToken};
+use std::io::{self, BufRead, Read, Write};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+#[test]
+fn stdin_lock_cancelled() {
+    let ct = CancellationToken::new();
+    ct.cancel();
+    // A cancelled token never reaches the actual read, so this does not block.
+    let mut stdin = Cancellable::new(io::stdin().lock(), ct);
+    let mut data = [0; 16];
+    let err = stdin.read(&mut data).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    let err = stdin.fill_buf().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    let err = stdin.read_line(&mut String::new()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn stdout_lock() {
+    let ct = CancellationToken::new();
+    let mut stdout = Cancellable::new(io::stdout().lock(), ct.clone());
+    writeln!(stdout, "stdout_lock test").unwrap();
+    stdout.flush().unwrap();
+    ct.cancel();
+    let err = writeln!(stdout, "not written").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn stderr_lock() {
+    let ct = CancellationToken::new();
+    let mut stderr = Cancellable::new(io::stderr().lock(), ct.clone());
+    ct.cancel();
+    let err = writeln!(stderr, "not written").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+}
+
+// A reader that blocks for a long time, like a terminal with no input.
+struct Blocked;
+
+impl Read for Blocked {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        std::thread::sleep(Duration::from_secs(60));
+        Ok(0)
+    }
+}
+
+// The pattern in `examples/stdin.rs`: the blocking reads happen in another
+// thread and the waiting side is the one that checks the token.
+#[test]
+fn reader_thread_pattern() {
+    let ct = CancellationToken::new();
+    let (tx, rx) = mpsc::channel::<io::Result<String>>();
+    std::thread::spawn(move || {
+        for line in io::BufReader::new(Blocked).lines() {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    std::thread::spawn({
+        let ct = ct.clone();
+        move || {
+            std::thread::sleep(Duration::from_millis(100));
+            ct.cancel();
+        }
+    });
+
+    let res = loop {
+        match rx.recv_timeout(Duration::from_millis(10)) {
+            Ok(_) => panic!("unexpected line"),
+            Err(RecvTimeoutError::Timeout) => {
+                if let Err(e) = ct.check() {
+                    break e;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => panic!("unexpected end"),
+        }
+    };
+    assert_eq!(res.kind(), io::ErrorKind::BrokenPipe);
+}
@@ -0,0 +1,32 @@
+use cancel_rw::{classify, Cancellable, CancellationToken, Outcome};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+#[test]
+fn tcp_duplex() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+    });
+
+    let ct = CancellationToken::new();
+    let mut stream = Cancellable::new(TcpStream::connect(addr).unwrap(), ct.clone());
+    stream.write_all(b"ping").unwrap();
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+    server.join().unwrap();
+
+    // The same token stops both directions, without touching the socket.
+    ct.cancel();
+    let err = stream.write_all(b"ping").unwrap_err();
+    assert_eq!(classify(&err), Outcome::Cancelled(None));
+    let err = stream.read(&mut buf).unwrap_err();
+    assert_eq!(classify(&err), Outcome::Cancelled(None));
+    // The socket itself is still usable, it sees the server close it.
+    assert_eq!(stream.get_mut().read(&mut buf).unwrap(), 0);
+}